@@ -1,27 +1,414 @@
 #![allow(unused_comparisons, dead_code)]
+use crate::cartridge::Cartridge;
+use eyre::{ensure, Result};
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::ops::RangeInclusive;
 
 const RAM_SIZE: usize = 64 * 1024;
+// 2KB of internal RAM, mirrored 4 times
+const INTERNAL_RAM: RangeInclusive<u16> = 0x0000..=0x1FFF;
+const INTERNAL_RAM_MASK: u16 = 0x07FF;
+const PRG_RAM: RangeInclusive<u16> = 0x6000..=0x7FFF;
+const PRG_RAM_MAX_SIZE: usize = 8 * 1024;
+const TRAINER_START: u16 = 0x7000;
+
+// Accesses a guarded region should never see
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GuardKind {
+    Write,
+    Execute,
+}
+
+// Something mapped in the CPU address space (cartridge, PPU registers, ...). A device
+// returns None / false for the addresses it doesn't decode, the bus then falls back
+// to its own RAM.
+pub trait BusDevice {
+    fn cpu_read(&self, addr: u16) -> Option<u8>;
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool;
+
+    // What the device shows in the $8000-$FFFF window, for `Bus::describe_map`. Nothing
+    // means the RAM stays visible there.
+    fn describe(&self) -> Vec<(RangeInclusive<u16>, String)> {
+        Vec::new()
+    }
+}
 
 pub struct Bus {
     pub ram: [u8; RAM_SIZE],
+    // Addresses pinned to their current value: writes to them are ignored (debugging aid)
+    frozen: HashSet<u16>,
+    // Called on every read, to observe memory mapped IO accesses
+    read_callback: Option<Box<dyn Fn(u16)>>,
+    // Regions test programs must not touch, and the last guarded write
+    guards: Vec<(RangeInclusive<u16>, GuardKind)>,
+    guard_hit: Option<(u16, GuardKind)>,
+    // Size of the cartridge PRG RAM at $6000-$7FFF, 0 when the cartridge has none
+    prg_ram_size: usize,
+    // Last value seen on the data bus, returned by reads of unmapped addresses
+    open_bus: Cell<u8>,
+    device: Option<Box<dyn BusDevice>>,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Bus {
     pub fn new() -> Self {
         let ram = [0; RAM_SIZE];
-        Self { ram }
+        Self {
+            ram,
+            frozen: HashSet::new(),
+            read_callback: None,
+            guards: Vec::new(),
+            guard_hit: None,
+            prg_ram_size: PRG_RAM_MAX_SIZE,
+            open_bus: Cell::new(0x00),
+            device: None,
+        }
     }
 
     pub fn write(&mut self, addr: u16, data: u8) {
+        if self.is_guarded(addr, GuardKind::Write) {
+            self.guard_hit = Some((addr, GuardKind::Write));
+        }
+        self.open_bus.set(data);
+        let addr = Self::mirror(addr);
+        if self.frozen.contains(&addr) {
+            return;
+        }
+        if let Some(device) = &mut self.device {
+            if device.cpu_write(addr, data) {
+                return;
+            }
+        }
+        if PRG_RAM.contains(&addr) {
+            if let Some(addr) = self.prg_ram_addr(addr) {
+                self.ram[addr] = data
+            }
+            return;
+        }
         if (0x0000..=0xFFFF).contains(&addr) {
             self.ram[addr as usize] = data
         }
     }
 
     pub fn read(&self, addr: u16) -> u8 {
-        if (0x0000..=0xFFFF).contains(&addr) {
-            return self.ram[addr as usize];
+        if let Some(callback) = &self.read_callback {
+            callback(addr)
+        }
+        let data = if let Some(data) = self.device_read(addr) {
+            data
+        } else if PRG_RAM.contains(&addr) {
+            match self.prg_ram_addr(addr) {
+                Some(addr) => self.ram[addr],
+                None => self.open_bus.get(),
+            }
+        } else {
+            self.ram[Self::mirror(addr) as usize]
+        };
+        self.open_bus.set(data);
+        data
+    }
+
+    fn device_read(&self, addr: u16) -> Option<u8> {
+        self.device.as_ref()?.cpu_read(addr)
+    }
+
+    pub fn set_device(&mut self, device: Option<Box<dyn BusDevice>>) {
+        self.device = device;
+    }
+
+    // The cartridge decodes $8000-$FFFF, its PRG ROM shadows the RAM there. The PRG RAM
+    // takes the size from the header, bank switched ones larger than the 8KB window only
+    // show their first bank. A trainer is copied to PRG RAM, so the cartridge is refused
+    // when there is none to hold it.
+    pub fn attach_cartridge(&mut self, cartridge: Cartridge) -> Result<()> {
+        let prg_ram_size = cartridge.prg_ram_size().min(PRG_RAM_MAX_SIZE);
+        ensure!(
+            cartridge.trainer().is_none() || prg_ram_size != 0,
+            "The trainer needs PRG RAM at ${:>04X}",
+            TRAINER_START
+        );
+        self.set_prg_ram_size(prg_ram_size)?;
+        if let Some(trainer) = cartridge.trainer() {
+            for (addr, byte) in (TRAINER_START..).zip(trainer) {
+                if let Some(addr) = self.prg_ram_addr(addr) {
+                    self.ram[addr] = *byte;
+                }
+            }
+        }
+        self.set_device(Some(Box::new(cartridge)));
+        Ok(())
+    }
+
+    // Internal RAM mirrors, the rest of the map is left as is
+    fn mirror(addr: u16) -> u16 {
+        if INTERNAL_RAM.contains(&addr) {
+            addr & INTERNAL_RAM_MASK
+        } else {
+            addr
         }
-        0x00
+    }
+
+    // Little endian word at `addr`, the high byte is read from `addr + 1` even across
+    // a page: the JMP indirect page bug is left to the addressing mode
+    pub fn read_u16(&self, addr: u16) -> u16 {
+        let low = self.read(addr) as u16;
+        let high = self.read(addr.wrapping_add(1)) as u16;
+        (high << 8) | low
+    }
+
+    pub fn write_u16(&mut self, addr: u16, value: u16) {
+        self.write(addr, (value & 0x00FF) as u8);
+        self.write(addr.wrapping_add(1), (value >> 8) as u8);
+    }
+
+    // Cartridge PRG RAM, as given by the iNES header (`prg_ram_size`, 0 for absent).
+    // Smaller RAMs are mirrored across the whole $6000-$7FFF window.
+    pub fn set_prg_ram_size(&mut self, size: usize) -> Result<()> {
+        ensure!(
+            size <= PRG_RAM_MAX_SIZE && (size == 0 || size.is_power_of_two()),
+            "Invalid PRG RAM size {}",
+            size
+        );
+        self.prg_ram_size = size;
+        Ok(())
+    }
+
+    pub fn has_prg_ram(&self) -> bool {
+        self.prg_ram_size != 0
+    }
+
+    fn prg_ram_addr(&self, addr: u16) -> Option<usize> {
+        if !self.has_prg_ram() {
+            return None;
+        }
+        let offset = (addr - PRG_RAM.start()) as usize % self.prg_ram_size;
+        Some(*PRG_RAM.start() as usize + offset)
+    }
+
+    // Read without notifying the read callback, for debugging tools
+    pub fn peek(&self, addr: u16) -> u8 {
+        if let Some(data) = self.device_read(addr) {
+            return data;
+        }
+        if PRG_RAM.contains(&addr) {
+            return match self.prg_ram_addr(addr) {
+                Some(addr) => self.ram[addr],
+                None => self.open_bus.get(),
+            };
+        }
+        self.ram[Self::mirror(addr) as usize]
+    }
+
+    // Pin `addr` to `value`, like a cheat freeze. Any later write to it is dropped.
+    pub fn freeze(&mut self, addr: u16, value: u8) {
+        self.frozen.remove(&Self::mirror(addr));
+        self.write(addr, value);
+        self.frozen.insert(Self::mirror(addr));
+    }
+
+    pub fn unfreeze(&mut self, addr: u16) {
+        self.frozen.remove(&Self::mirror(addr));
+    }
+
+    pub fn is_frozen(&self, addr: u16) -> bool {
+        self.frozen.contains(&Self::mirror(addr))
+    }
+
+    pub fn set_read_callback(&mut self, callback: Option<Box<dyn Fn(u16)>>) {
+        self.read_callback = callback;
+    }
+
+    // Set a whole region to `value`, e.g. to poison memory before running a program
+    pub fn fill(&mut self, range: RangeInclusive<u16>, value: u8) {
+        for addr in range {
+            self.write(addr, value);
+        }
+    }
+
+    pub fn add_guard(&mut self, range: RangeInclusive<u16>, kind: GuardKind) {
+        self.guards.push((range, kind));
+    }
+
+    pub fn is_guarded(&self, addr: u16, kind: GuardKind) -> bool {
+        self.guards
+            .iter()
+            .any(|(range, guard)| *guard == kind && range.contains(&addr))
+    }
+
+    // Guarded write that happened since the last call, if any
+    pub fn take_guard_hit(&mut self) -> Option<(u16, GuardKind)> {
+        self.guard_hit.take()
+    }
+
+    // Where the program code lives: the PRG ROM window of a cartridge
+    pub fn code_region(&self) -> RangeInclusive<u16> {
+        0x8000..=0xFFFF
+    }
+
+    // Human readable layout of the CPU address space
+    pub fn describe_map(&self) -> Vec<(RangeInclusive<u16>, String)> {
+        let prg_ram = match self.prg_ram_size {
+            0 => "PRG RAM (absent, open bus)".into(),
+            PRG_RAM_MAX_SIZE => "PRG RAM (8KB)".into(),
+            size => format!("PRG RAM ({}KB, mirrored)", size / 1024),
+        };
+        let mut map = vec![
+            (INTERNAL_RAM, "RAM (2KB, mirrored)".into()),
+            (0x2000..=0x5FFF, "RAM (flat)".into()),
+            (PRG_RAM, prg_ram),
+        ];
+        let device = self.device.as_ref().map(|device| device.describe());
+        match device {
+            Some(regions) if !regions.is_empty() => map.extend(regions),
+            _ => map.push((0x8000..=0xFFFF, "RAM (flat)".into())),
+        }
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_flat_map() {
+        let bus = Bus::new();
+        let map = bus.describe_map();
+        assert_eq!(map.len(), 4);
+        assert_eq!(map[0].0, 0x0000..=0x1FFF);
+        assert_eq!(map[0].1, "RAM (2KB, mirrored)");
+        assert_eq!(map[1].0, 0x2000..=0x5FFF);
+        assert_eq!(map[1].1, "RAM (flat)");
+        assert_eq!(map[2].0, 0x6000..=0x7FFF);
+        assert_eq!(map[2].1, "PRG RAM (8KB)");
+        assert_eq!(map[3].0, 0x8000..=0xFFFF);
+        assert_eq!(map[3].1, "RAM (flat)");
+    }
+
+    #[test]
+    fn test_internal_ram_mirroring() {
+        let mut bus = Bus::new();
+        bus.write(0x0000, 0x42);
+        assert_eq!(bus.read(0x0800), 0x42);
+        assert_eq!(bus.read(0x1000), 0x42);
+        assert_eq!(bus.read(0x1800), 0x42);
+
+        bus.write(0x1FFF, 0x24);
+        assert_eq!(bus.peek(0x07FF), 0x24);
+        // outside of the internal RAM
+        bus.write(0x2000, 0x99);
+        assert_eq!(bus.read(0x0000), 0x42);
+    }
+
+    #[test]
+    fn test_prg_ram_absent() {
+        let mut bus = Bus::new();
+        bus.write(0x6000, 0x42);
+        assert_eq!(bus.read(0x6000), 0x42);
+
+        bus.set_prg_ram_size(0).unwrap();
+        assert!(!bus.has_prg_ram());
+        bus.write(0x0010, 0x99);
+        bus.write(0x6000, 0x11);
+        assert_eq!(bus.read(0x0010), 0x99);
+        // the last value on the data bus, not the stored one
+        assert_eq!(bus.read(0x6000), 0x99);
+        assert_eq!(bus.read(0x7FFF), 0x99);
+        assert_eq!(bus.describe_map()[2].0, 0x6000..=0x7FFF);
+        assert_eq!(bus.describe_map()[2].1, "PRG RAM (absent, open bus)");
+    }
+
+    #[test]
+    fn test_prg_ram_mirroring() {
+        let mut bus = Bus::new();
+        bus.set_prg_ram_size(2 * 1024).unwrap();
+        assert!(bus.set_prg_ram_size(3 * 1024).is_err());
+        assert!(bus.set_prg_ram_size(16 * 1024).is_err());
+        bus.write(0x6001, 0x42);
+        assert_eq!(bus.read(0x6801), 0x42);
+        assert_eq!(bus.describe_map()[2].1, "PRG RAM (2KB, mirrored)");
+        assert_eq!(bus.read(0x7801), 0x42);
+    }
+
+    #[test]
+    fn test_read_write_u16() {
+        let mut bus = Bus::new();
+        bus.write_u16(0x8000, 0x1234);
+        assert_eq!(bus.read(0x8000), 0x34);
+        assert_eq!(bus.read(0x8001), 0x12);
+        assert_eq!(bus.read_u16(0x8000), 0x1234);
+
+        // no page wrap, unlike JMP ($80FF)
+        bus.write(0x80FF, 0xCD);
+        bus.write(0x8100, 0xAB);
+        bus.write(0x8000, 0xEF);
+        assert_eq!(bus.read_u16(0x80FF), 0xABCD);
+        bus.write_u16(0x81FF, 0x5678);
+        assert_eq!(bus.read(0x81FF), 0x78);
+        assert_eq!(bus.read(0x8200), 0x56);
+
+        // the address itself wraps at the top of memory
+        bus.write_u16(0xFFFF, 0x9ABC);
+        assert_eq!(bus.read(0xFFFF), 0xBC);
+        assert_eq!(bus.read(0x0000), 0x9A);
+        assert_eq!(bus.read_u16(0xFFFF), 0x9ABC);
+    }
+
+    // Answers for its window only, and swallows the writes to it
+    struct Constant {
+        range: RangeInclusive<u16>,
+        value: u8,
+    }
+
+    impl BusDevice for Constant {
+        fn cpu_read(&self, addr: u16) -> Option<u8> {
+            self.range.contains(&addr).then_some(self.value)
+        }
+
+        fn cpu_write(&mut self, addr: u16, _data: u8) -> bool {
+            self.range.contains(&addr)
+        }
+    }
+
+    #[test]
+    fn test_device() {
+        let mut bus = Bus::new();
+        bus.write(0x8000, 0x11);
+        bus.write(0x4020, 0x22);
+        bus.set_device(Some(Box::new(Constant {
+            range: 0x8000..=0xFFFF,
+            value: 0xEA,
+        })));
+
+        assert_eq!(bus.read(0x8000), 0xEA);
+        assert_eq!(bus.peek(0x8000), 0xEA);
+        // declined, back to RAM
+        assert_eq!(bus.read(0x4020), 0x22);
+        bus.write(0x4020, 0x33);
+        assert_eq!(bus.read(0x4020), 0x33);
+        // claimed writes don't reach the RAM
+        bus.write(0x8000, 0x44);
+        bus.set_device(None);
+        assert_eq!(bus.read(0x8000), 0x11);
+    }
+
+    #[test]
+    fn test_fill() {
+        let mut bus = Bus::new();
+        bus.fill(0x0010..=0x001F, 0xAA);
+        assert!((0x0010..=0x001F).all(|addr| bus.read(addr) == 0xAA));
+        assert_eq!(bus.read(0x000F), 0x00);
+        assert_eq!(bus.read(0x0020), 0x00);
+
+        // up to the very last address
+        bus.fill(0xFFF0..=0xFFFF, 0x55);
+        assert!((0xFFF0..=0xFFFF).all(|addr| bus.read(addr) == 0x55));
+        assert_eq!(bus.read(0xFFEF), 0x00);
     }
 }
@@ -0,0 +1,186 @@
+#![allow(dead_code)]
+// Cartridge boards, as seen from the CPU: https://www.nesdev.org/wiki/Mapper
+use crate::bus::BusDevice;
+use crate::ines::INes;
+use eyre::{ensure, Result};
+use std::ops::RangeInclusive;
+
+const PRG_ROM_START: u16 = 0x8000;
+
+// NROM (mapper 0): 16KB or 32KB of PRG ROM, 8KB of CHR ROM and no bank switching
+pub struct Cartridge {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    trainer: Option<Vec<u8>>,
+    prg_ram_size: usize,
+}
+
+impl Cartridge {
+    pub fn new(rom: &INes) -> Result<Self> {
+        let mapper = rom.header().mapper_number();
+        ensure!(mapper == 0, "Unsupported mapper {}", mapper);
+        let prg_rom = rom.prg_rom();
+        ensure!(
+            prg_rom.len() == 16 * 1024 || prg_rom.len() == 32 * 1024,
+            "Unsupported PRG ROM size {}, expected 16KB or 32KB",
+            prg_rom.len()
+        );
+
+        Ok(Self {
+            prg_rom: prg_rom.to_vec(),
+            chr_rom: rom.chr_rom().to_vec(),
+            trainer: rom.trainer().map(|trainer| trainer.to_vec()),
+            prg_ram_size: rom.header().prg_ram_size(),
+        })
+    }
+
+    // Pattern tables, for the PPU
+    pub fn chr_rom(&self) -> &[u8] {
+        &self.chr_rom
+    }
+
+    // PRG RAM at $6000-$7FFF declared by the header, in bytes (0 for none)
+    pub fn prg_ram_size(&self) -> usize {
+        self.prg_ram_size
+    }
+
+    // Loaded in PRG RAM at $7000-$71FF when the cartridge is plugged
+    pub fn trainer(&self) -> Option<&[u8]> {
+        self.trainer.as_deref()
+    }
+}
+
+impl BusDevice for Cartridge {
+    // A single 16KB bank shows at both $8000 and $C000
+    fn cpu_read(&self, addr: u16) -> Option<u8> {
+        if addr < PRG_ROM_START {
+            return None;
+        }
+        let mask = self.prg_rom.len() - 1;
+        Some(self.prg_rom[(addr - PRG_ROM_START) as usize & mask])
+    }
+
+    // ROM can't be written, and NROM has no registers to catch writes
+    fn cpu_write(&mut self, _addr: u16, _data: u8) -> bool {
+        false
+    }
+
+    fn describe(&self) -> Vec<(RangeInclusive<u16>, String)> {
+        let size = self.prg_rom.len() / 1024;
+        let mirrored = if size == 16 { ", mirrored" } else { "" };
+        vec![(
+            PRG_ROM_START..=0xFFFF,
+            format!("PRG ROM (NROM, {}KB{})", size, mirrored),
+        )]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cpu::Cpu;
+    use crate::ines;
+
+    fn nrom(prg_rom: Vec<u8>) -> INes {
+        INes::new(ines::image(0x00, &[], &prg_rom, &[0x55; 8 * 1024])).unwrap()
+    }
+
+    #[test]
+    fn test_nrom_16k_mirroring() {
+        let mut prg_rom = vec![0xEA; 16 * 1024];
+        prg_rom[0x0000] = 0x4C;
+        prg_rom[0x3FFF] = 0xC5;
+        let cartridge = Cartridge::new(&nrom(prg_rom)).unwrap();
+
+        assert_eq!(cartridge.cpu_read(0x8000), Some(0x4C));
+        assert_eq!(cartridge.cpu_read(0xC000), Some(0x4C));
+        assert_eq!(cartridge.cpu_read(0xBFFF), Some(0xC5));
+        assert_eq!(cartridge.cpu_read(0xFFFF), Some(0xC5));
+        assert_eq!(cartridge.cpu_read(0x7FFF), None);
+        assert_eq!(cartridge.chr_rom().len(), 8 * 1024);
+    }
+
+    #[test]
+    fn test_nrom_32k() {
+        let mut prg_rom = vec![0xEA; 32 * 1024];
+        prg_rom[0x0000] = 0x01;
+        prg_rom[0x4000] = 0x02;
+        let cartridge = Cartridge::new(&nrom(prg_rom)).unwrap();
+
+        assert_eq!(cartridge.cpu_read(0x8000), Some(0x01));
+        assert_eq!(cartridge.cpu_read(0xC000), Some(0x02));
+        assert!(Cartridge::new(&nrom(vec![0xEA; 48 * 1024])).is_err());
+    }
+
+    #[test]
+    fn test_attach() {
+        // one 16KB bank: JMP $C5F5 at its start, reset vector at $C004
+        let mut prg_rom = vec![0xEA; 16 * 1024];
+        prg_rom[..3].copy_from_slice(&[0x4C, 0xF5, 0xC5]);
+        prg_rom[0x3FFC..0x3FFE].copy_from_slice(&[0x04, 0xC0]);
+
+        let mut bus = Bus::new();
+        bus.attach_cartridge(Cartridge::new(&nrom(prg_rom)).unwrap())
+            .unwrap();
+        assert_eq!(bus.read(0xC000), 0x4C);
+        assert_eq!(bus.read(0x8000), 0x4C);
+        assert_eq!(bus.read(0xC002), 0xC5);
+
+        // 8KB of PRG RAM by default
+        assert!(bus.has_prg_ram());
+        bus.write(0x6000, 0x42);
+        assert_eq!(bus.read(0x7FFF), 0x00);
+
+        let mut cpu = Cpu::new(bus);
+        cpu.power_on();
+        assert_eq!(cpu.trace_line().get(..4), Some("C004"));
+    }
+
+    #[test]
+    fn test_describe_map() {
+        let mut bus = Bus::new();
+        let cartridge = Cartridge::new(&nrom(vec![0xEA; 16 * 1024])).unwrap();
+        bus.attach_cartridge(cartridge).unwrap();
+        let map = bus.describe_map();
+        assert_eq!(map.len(), 4);
+        assert_eq!(map[2], (0x6000..=0x7FFF, "PRG RAM (8KB)".into()));
+        assert_eq!(
+            map[3],
+            (0x8000..=0xFFFF, "PRG ROM (NROM, 16KB, mirrored)".into())
+        );
+
+        let mut bytes = ines::image(0x00, &[], &[0xEA; 32 * 1024], &[0x55; 8 * 1024]);
+        bytes[10] = 0x10; // no PRG RAM
+        let cartridge = Cartridge::new(&INes::new(bytes).unwrap()).unwrap();
+        bus.attach_cartridge(cartridge).unwrap();
+        let map = bus.describe_map();
+        assert_eq!(map[2].1, "PRG RAM (absent, open bus)");
+        assert_eq!(map[3], (0x8000..=0xFFFF, "PRG ROM (NROM, 32KB)".into()));
+    }
+
+    #[test]
+    fn test_trainer() {
+        let trainer: Vec<u8> = (0..512).map(|i| i as u8).collect();
+        let bytes = ines::image(0x00, &trainer, &[0xEA; 16 * 1024], &[0x55; 8 * 1024]);
+        let rom = INes::new(bytes).unwrap();
+
+        let mut bus = Bus::new();
+        bus.attach_cartridge(Cartridge::new(&rom).unwrap()).unwrap();
+        assert_eq!(bus.read(0x7000), 0x00);
+        assert_eq!(bus.read(0x7001), 0x01);
+        assert_eq!(bus.read(0x71FF), 0xFF);
+        assert_eq!(bus.read(0x7200), 0x00);
+        assert_eq!(bus.read(0x8000), 0xEA);
+
+        // nowhere to load it: the header says there is no PRG RAM
+        let mut bytes = ines::image(0x00, &trainer, &[0xEA; 16 * 1024], &[0x55; 8 * 1024]);
+        bytes[10] = 0x10;
+        let rom = INes::new(bytes).unwrap();
+        let mut bus = Bus::new();
+        let err = bus.attach_cartridge(Cartridge::new(&rom).unwrap());
+        assert!(err.unwrap_err().to_string().contains("PRG RAM"));
+        assert!(bus.has_prg_ram());
+        assert_eq!(bus.peek(0x8000), 0x00);
+    }
+}
@@ -1,9 +1,10 @@
 #![allow(clippy::upper_case_acronyms)]
 use super::{AddrMode, CpuCore};
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Kind {
     IMP,
+    ACC,
     IMM,
     ZP0,
     ZPX,
@@ -17,6 +18,17 @@ pub enum Kind {
     IZY,
 }
 
+impl Kind {
+    // Number of operand bytes following the opcode
+    pub fn operand_len(&self) -> u16 {
+        match self {
+            Kind::IMP | Kind::ACC => 0,
+            Kind::IMM | Kind::ZP0 | Kind::ZPX | Kind::ZPY | Kind::REL | Kind::IZX | Kind::IZY => 1,
+            Kind::ABS | Kind::ABX | Kind::ABY | Kind::IND => 2,
+        }
+    }
+}
+
 pub struct IMP {}
 
 impl AddrMode for IMP {
@@ -30,11 +42,25 @@ impl AddrMode for IMP {
     }
 }
 
+// Accumulator: the operation works on A, e.g. `ASL A`
+pub struct ACC {}
+
+impl AddrMode for ACC {
+    fn run(&self, cpu: &mut CpuCore) -> u8 {
+        cpu.fetched = cpu.a;
+        0
+    }
+
+    fn kind(&self) -> Kind {
+        Kind::ACC
+    }
+}
+
 pub struct IMM {}
 impl AddrMode for IMM {
     fn run(&self, cpu: &mut CpuCore) -> u8 {
         cpu.addr_abs = cpu.pc;
-        cpu.pc += 1;
+        cpu.pc = cpu.pc.wrapping_add(1);
         0
     }
     fn kind(&self) -> Kind {
@@ -46,7 +72,7 @@ pub struct ZP0 {}
 impl AddrMode for ZP0 {
     fn run(&self, cpu: &mut CpuCore) -> u8 {
         cpu.addr_abs = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
+        cpu.pc = cpu.pc.wrapping_add(1);
         0
     }
     fn kind(&self) -> Kind {
@@ -60,7 +86,7 @@ impl AddrMode for ZPX {
         cpu.addr_abs = cpu.read(cpu.pc) as u16;
         cpu.addr_abs = cpu.addr_abs.wrapping_add(cpu.x as u16);
         cpu.addr_abs &= 0x00FF;
-        cpu.pc += 1;
+        cpu.pc = cpu.pc.wrapping_add(1);
         0
     }
     fn kind(&self) -> Kind {
@@ -74,7 +100,7 @@ impl AddrMode for ZPY {
         cpu.addr_abs = cpu.read(cpu.pc) as u16;
         cpu.addr_abs = cpu.addr_abs.wrapping_add(cpu.y as u16);
         cpu.addr_abs &= 0x00FF;
-        cpu.pc += 1;
+        cpu.pc = cpu.pc.wrapping_add(1);
         0
     }
     fn kind(&self) -> Kind {
@@ -86,7 +112,7 @@ pub struct REL {}
 impl AddrMode for REL {
     fn run(&self, cpu: &mut CpuCore) -> u8 {
         cpu.addr_rel = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
+        cpu.pc = cpu.pc.wrapping_add(1);
         if cpu.addr_rel & 0x0080 != 0 {
             // relative range between -128 and +127 so we sign extend
             cpu.addr_rel |= 0xFF00;
@@ -101,12 +127,8 @@ impl AddrMode for REL {
 pub struct ABS {}
 impl AddrMode for ABS {
     fn run(&self, cpu: &mut CpuCore) -> u8 {
-        let low: u16 = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
-        let high: u16 = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
-
-        cpu.addr_abs = (high << 8) | low;
+        cpu.addr_abs = cpu.read_u16(cpu.pc);
+        cpu.pc = cpu.pc.wrapping_add(2);
         0
     }
     fn kind(&self) -> Kind {
@@ -117,16 +139,16 @@ impl AddrMode for ABS {
 pub struct ABX {}
 impl AddrMode for ABX {
     fn run(&self, cpu: &mut CpuCore) -> u8 {
-        let low: u16 = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
-        let high: u16 = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
+        let base = cpu.read_u16(cpu.pc);
+        cpu.pc = cpu.pc.wrapping_add(2);
 
-        cpu.addr_abs = (high << 8) | low;
-        cpu.addr_abs = cpu.addr_abs.wrapping_add(cpu.x as u16);
+        cpu.addr_abs = base.wrapping_add(cpu.x as u16);
 
         // maybe an extra clock cycle is necessary
-        let extra_clock_cycle = (cpu.addr_abs & 0xFF00) != (high << 8);
+        let extra_clock_cycle = (cpu.addr_abs & 0xFF00) != (base & 0xFF00);
+        if extra_clock_cycle {
+            cpu.dummy_read((base & 0xFF00) | (cpu.addr_abs & 0x00FF));
+        }
         extra_clock_cycle as u8
     }
     fn kind(&self) -> Kind {
@@ -137,16 +159,16 @@ impl AddrMode for ABX {
 pub struct ABY {}
 impl AddrMode for ABY {
     fn run(&self, cpu: &mut CpuCore) -> u8 {
-        let low: u16 = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
-        let high: u16 = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
+        let base = cpu.read_u16(cpu.pc);
+        cpu.pc = cpu.pc.wrapping_add(2);
 
-        cpu.addr_abs = (high << 8) | low;
-        cpu.addr_abs = cpu.addr_abs.wrapping_add(cpu.y as u16);
+        cpu.addr_abs = base.wrapping_add(cpu.y as u16);
 
         // maybe an extra clock cycle is necessary
-        let extra_clock_cycle = (cpu.addr_abs & 0xFF00) != (high << 8);
+        let extra_clock_cycle = (cpu.addr_abs & 0xFF00) != (base & 0xFF00);
+        if extra_clock_cycle {
+            cpu.dummy_read((base & 0xFF00) | (cpu.addr_abs & 0x00FF));
+        }
         extra_clock_cycle as u8
     }
     fn kind(&self) -> Kind {
@@ -157,22 +179,16 @@ impl AddrMode for ABY {
 pub struct IND {}
 impl AddrMode for IND {
     fn run(&self, cpu: &mut CpuCore) -> u8 {
-        let ptr_low: u16 = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
-        let ptr_high: u16 = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
-
-        let ptr: u16 = (ptr_high << 8) | ptr_low;
+        let ptr = cpu.read_u16(cpu.pc);
+        cpu.pc = cpu.pc.wrapping_add(2);
 
-        if ptr_low == 0x00FF {
+        if ptr & 0x00FF == 0x00FF {
             // page boundary hardware bug
             let low: u16 = cpu.read(ptr) as u16;
             let high: u16 = cpu.read(ptr & 0xFF00) as u16;
             cpu.addr_abs = (high << 8) | low
         } else {
-            let low: u16 = cpu.read(ptr) as u16;
-            let high: u16 = cpu.read(ptr + 1) as u16;
-            cpu.addr_abs = (high << 8) | low
+            cpu.addr_abs = cpu.read_u16(ptr)
         }
         0
     }
@@ -184,13 +200,13 @@ impl AddrMode for IND {
 pub struct IZX {}
 impl AddrMode for IZX {
     fn run(&self, cpu: &mut CpuCore) -> u8 {
-        let ptr: u16 = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
+        let ptr = cpu.read(cpu.pc);
+        cpu.pc = cpu.pc.wrapping_add(1);
 
-        // ptr is really 8 bits, and x too, so ptr + x + 1 can't overflow in u16
-        let ptr_x: u16 = ptr + (cpu.x as u16);
-        let low: u16 = cpu.read(ptr_x) as u16;
-        let high: u16 = cpu.read(ptr_x + 1) as u16;
+        // the pointer lives in the zero page: u8 math keeps both reads within it
+        let ptr_x = ptr.wrapping_add(cpu.x);
+        let low: u16 = cpu.read(ptr_x as u16) as u16;
+        let high: u16 = cpu.read(ptr_x.wrapping_add(1) as u16) as u16;
 
         cpu.addr_abs = (high << 8) | low;
         0
@@ -203,17 +219,20 @@ impl AddrMode for IZX {
 pub struct IZY {}
 impl AddrMode for IZY {
     fn run(&self, cpu: &mut CpuCore) -> u8 {
-        let ptr: u16 = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
+        let ptr = cpu.read(cpu.pc);
+        cpu.pc = cpu.pc.wrapping_add(1);
 
-        // ptr is really 8 bits, so ptr + x + 1 can't overflow in u16
-        let low: u16 = cpu.read(ptr) as u16;
-        let high: u16 = cpu.read(ptr + 1) as u16;
+        // the pointer lives in the zero page: the high byte of $FF is read from $00
+        let low: u16 = cpu.read(ptr as u16) as u16;
+        let high: u16 = cpu.read(ptr.wrapping_add(1) as u16) as u16;
 
         cpu.addr_abs = (high << 8) | low;
         cpu.addr_abs = cpu.addr_abs.wrapping_add(cpu.y as u16);
 
         let extra_cycle = (cpu.addr_abs & 0xFF00) != (high << 8);
+        if extra_cycle {
+            cpu.dummy_read((high << 8) | (cpu.addr_abs & 0x00FF));
+        }
         extra_cycle as u8
     }
     fn kind(&self) -> Kind {
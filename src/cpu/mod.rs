@@ -1,17 +1,22 @@
 #![allow(dead_code)]
 /// Almost everything in this files comes from NesDev: https://www.nesdev.org/wiki/CPU
 use bitflags::bitflags;
+use serde::Serialize;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
-use std::rc::Rc;
-use std::sync::RwLock;
+use std::ops::RangeInclusive;
 
-use crate::bus::Bus;
+use crate::bus::{Bus, GuardKind};
+use crate::region::Region;
+use crate::symbols::{self, Symbols};
 
 mod addr_modes;
 mod operations;
 
+pub use addr_modes::Kind;
+
 bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
     pub struct Flags: u8 {
         const C = 1 << 0; // Carry Bit
         const Z = 1 << 1; // Zero
@@ -24,6 +29,11 @@ bitflags! {
     }
 }
 
+// The reset is an interrupt sequence with the stack writes turned into reads: 7 cycles
+// like on hardware, so the first instruction runs at CYC:7 as in nestest.log (it used
+// to be 8, the test0 golden trace follows this offset)
+const RESET_CYCLES: usize = 7;
+
 trait AddrMode {
     // Addressing modes return 1 if additional clock cycles are necessary
     fn run(&self, cpu: &mut CpuCore) -> u8;
@@ -32,7 +42,7 @@ trait AddrMode {
 
 trait Operation {
     // Some opcode requires additional clock cycles conditionally too
-    fn run(&self, opcodse: &HashMap<u8, Opcode>, cpu: &mut CpuCore) -> u8;
+    fn run(&self, opcodse: &Opcodes, cpu: &mut CpuCore) -> u8;
 }
 
 struct Opcode {
@@ -40,6 +50,8 @@ struct Opcode {
     addr_mode: Box<dyn AddrMode>,
     op: Box<dyn Operation>,
     cycles: usize,
+    // false for the placeholder of the table slots without an opcode
+    known: bool,
 }
 
 macro_rules! opcode {
@@ -49,10 +61,135 @@ macro_rules! opcode {
             addr_mode: Box::new(addr_modes::$mode {}),
             op: Box::new(operations::$name {}),
             cycles: $cycles,
+            known: true,
         }
     };
 }
 
+// Dispatch table indexed by the opcode byte. Unknown opcodes hold the XXX placeholder:
+// a 1 byte, 2 cycles no-op that is never run, fetching it jams the CPU.
+struct Opcodes(Box<[Opcode; 256]>);
+
+impl Opcodes {
+    fn new() -> Self {
+        Self(Box::new(std::array::from_fn(|_| Opcode {
+            known: false,
+            ..opcode!(XXX, IMP, 2)
+        })))
+    }
+
+    // None for the XXX placeholder
+    fn get(&self, byte: u8) -> Option<&Opcode> {
+        let opcode = &self[byte];
+        opcode.known.then_some(opcode)
+    }
+}
+
+impl std::ops::Index<u8> for Opcodes {
+    type Output = Opcode;
+
+    fn index(&self, byte: u8) -> &Opcode {
+        &self.0[byte as usize]
+    }
+}
+
+impl std::ops::IndexMut<u8> for Opcodes {
+    fn index_mut(&mut self, byte: u8) -> &mut Opcode {
+        &mut self.0[byte as usize]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InterruptKind {
+    Nmi,
+    Irq,
+    Brk,
+}
+
+impl InterruptKind {
+    pub fn vector(&self) -> u16 {
+        match self {
+            InterruptKind::Nmi => 0xFFFA,
+            InterruptKind::Irq | InterruptKind::Brk => 0xFFFE,
+        }
+    }
+}
+
+impl std::str::FromStr for InterruptKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "nmi" => Ok(InterruptKind::Nmi),
+            "irq" => Ok(InterruptKind::Irq),
+            "brk" => Ok(InterruptKind::Brk),
+            _ => Err(format!("'{}' is not an interrupt (nmi, irq or brk)", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for InterruptKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            InterruptKind::Nmi => "NMI",
+            InterruptKind::Irq => "IRQ",
+            InterruptKind::Brk => "BRK",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// An interrupt taken: `pc` is the return address pushed on the stack (past the
+// padding byte for BRK) and `cycle` the clock count when the sequence started
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InterruptEvent {
+    pub kind: InterruptKind,
+    pub pc: u16,
+    pub vector: u16,
+    pub cycle: usize,
+}
+
+// CPU state after a run, serialized by `--dump-state`. `p` is the status byte as in
+// trace lines, `flags` the same bits by name.
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub pc: u16,
+    pub p: u8,
+    pub flags: BTreeMap<&'static str, bool>,
+    pub cycles: usize,
+    pub instructions: usize,
+    pub jammed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<MemoryDump>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MemoryDump {
+    pub start: u16,
+    pub bytes: Vec<u8>,
+}
+
+// A guarded memory region was accessed by the instruction at `pc`
+#[derive(Debug, PartialEq)]
+pub struct GuardViolation {
+    pub pc: u16,
+    pub addr: u16,
+    pub kind: GuardKind,
+}
+
+// One decoded instruction: `len` counts the opcode byte too
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Instruction {
+    pub addr: u16,
+    pub opcode: u8,
+    pub operand: u16,
+    pub len: u8,
+}
+
 pub struct CpuCore {
     pub a: u8,
     pub x: u8,
@@ -72,21 +209,51 @@ pub struct CpuCore {
     cycles: usize,
     // Total number of clock ticks from reset
     clock_count: usize,
-
-    // Link to the underlying bus
-    bus: Rc<RwLock<Bus>>,
+    // Cycles the CPU is halted for (DMA), paid before the next instruction
+    stall_cycles: usize,
+    // Total number of instructions executed
+    instruction_count: usize,
+
+    // Perform the dummy bus accesses of the real hardware (visible to memory mapped IO)
+    cycle_accurate: bool,
+
+    // Taken / not taken counts for each conditional branch, by address
+    log_branches: bool,
+    branch_stats: HashMap<u16, (u64, u64)>,
+
+    // Every interrupt taken, in order
+    log_interrupts: bool,
+    interrupt_log: Vec<InterruptEvent>,
+
+    // Set when a bus guard fires: the CPU halts until the next reset
+    guard_violation: Option<GuardViolation>,
+    // Set when an unknown opcode is fetched: like the KIL opcodes, the CPU stops with
+    // pc on the opcode until the next reset
+    jammed: bool,
+
+    // Interrupt request lines driven by the mapper / APU / PPU. IRQ is level triggered,
+    // NMI edge triggered: a rising edge latches `nmi_pending` until it is taken.
+    irq_line: bool,
+    nmi_line: bool,
+    nmi_pending: bool,
+
+    // (address, old value, new value) of the writes done by the last instruction
+    writes: Vec<(u16, u8, u8)>,
+
+    // The CPU owns the bus, the UI looks at it through `Cpu::bus`
+    bus: Bus,
 }
 
 impl CpuCore {
     fn new(bus: Bus) -> Self {
-        let bus = Rc::new(RwLock::new(bus));
         Self {
             a: 0,
             x: 0,
             y: 0,
             sp: 0,
             pc: 0,
-            status: Flags::empty(),
+            // U is not a real flag: it always reads as 1
+            status: Flags::U,
             fetched: 0,
             temp: 0,
             addr_abs: 0,
@@ -94,119 +261,443 @@ impl CpuCore {
             opcode: 0,
             cycles: 0,
             clock_count: 0,
+            stall_cycles: 0,
+            instruction_count: 0,
+            cycle_accurate: false,
+            log_branches: false,
+            branch_stats: HashMap::new(),
+            log_interrupts: false,
+            interrupt_log: Vec::new(),
+            guard_violation: None,
+            jammed: false,
+            irq_line: false,
+            nmi_line: false,
+            nmi_pending: false,
+            writes: Vec::new(),
             bus,
         }
     }
 
     fn read(&self, addr: u16) -> u8 {
-        self.bus.read().expect("Failed to get bus").read(addr)
+        self.bus.read(addr)
+    }
+
+    fn read_u16(&self, addr: u16) -> u16 {
+        self.bus.read_u16(addr)
+    }
+
+    // Read without side effects, for traces
+    fn peek(&self, addr: u16) -> u8 {
+        self.bus.peek(addr)
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        let old = self.bus.peek(addr);
+        self.bus.write(addr, value);
+        self.writes.push((addr, old, self.bus.peek(addr)));
+    }
+
+    // When indexing crosses a page, the 6502 first reads at the address with the
+    // uncorrected high byte, then at the right one
+    fn dummy_read(&self, addr: u16) {
+        if self.cycle_accurate {
+            let _ = self.read(addr);
+        }
+    }
+
+    // Last opcode byte fetched, i.e. the instruction in flight or the one that just completed
+    pub fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    // Raw status register, as shown in traces
+    pub fn status_byte(&self) -> u8 {
+        self.status.bits()
+    }
+
+    pub fn set_a(&mut self, value: u8) {
+        self.a = value;
+    }
+
+    pub fn set_x(&mut self, value: u8) {
+        self.x = value;
+    }
+
+    pub fn set_y(&mut self, value: u8) {
+        self.y = value;
+    }
+
+    pub fn set_sp(&mut self, value: u8) {
+        self.sp = value;
     }
 
-    fn write(&self, addr: u16, value: u8) {
-        self.bus
-            .write()
-            .expect("Failed to get bus")
-            .write(addr, value)
+    // The next instruction is fetched from `value`
+    pub fn set_pc(&mut self, value: u16) {
+        self.pc = value;
     }
 
     pub fn get_flag(&self, flag: Flags) -> bool {
         self.status.contains(flag)
     }
 
+    // U always reads as 1 and B doesn't exist in the register: it only shows up in the
+    // copy of the status pushed on the stack
     pub fn set_flag(&mut self, flag: Flags, on_off: bool) {
         self.status.set(flag, on_off);
+        self.status.insert(Flags::U);
+        self.status.remove(Flags::B);
     }
 
-    fn fetch(&mut self, opcodes: &HashMap<u8, Opcode>) -> u8 {
-        let xxx = opcode!(XXX, IMP, 0);
+    // The stack lives in page 1, sp points to the next free slot and grows down
+    fn push(&mut self, value: u8) {
+        self.write(0x0100 | self.sp as u16, value);
+        self.sp = self.sp.wrapping_sub(1);
+    }
 
-        let Opcode { addr_mode, .. } = match opcodes.get(&self.opcode) {
-            None => &xxx,
-            Some(opcode) => opcode,
-        };
+    fn pull(&mut self) -> u8 {
+        self.sp = self.sp.wrapping_add(1);
+        self.read(0x0100 | self.sp as u16)
+    }
+
+    // High byte first, so the word reads little endian in memory
+    fn push_u16(&mut self, value: u16) {
+        self.push((value >> 8) as u8);
+        self.push((value & 0x00FF) as u8);
+    }
+
+    fn pull_u16(&mut self) -> u16 {
+        let low = self.pull() as u16;
+        let high = self.pull() as u16;
+        (high << 8) | low
+    }
+
+    // Interrupt sequence: save pc and status (B only set for BRK), mask IRQs and jump
+    // through the vector of `kind`
+    fn interrupt(&mut self, kind: InterruptKind) {
+        let vector = kind.vector();
+        if self.log_interrupts {
+            self.interrupt_log.push(InterruptEvent {
+                kind,
+                pc: self.pc,
+                vector,
+                cycle: self.clock_count,
+            });
+        }
+
+        self.push_u16(self.pc);
+        let status = self.status_for_push(kind == InterruptKind::Brk);
+        self.push(status);
+        // set after the push, the saved copy keeps the interrupted code's I
+        self.set_flag(Flags::I, true);
+
+        self.addr_abs = vector;
+        self.pc = self.read_u16(self.addr_abs);
+    }
+
+    // Non maskable interrupt, e.g. the PPU vblank
+    fn nmi(&mut self) {
+        self.interrupt(InterruptKind::Nmi);
+        self.cycles = 8;
+    }
+
+    // Maskable interrupt, ignored while I is set
+    fn irq(&mut self) {
+        if self.get_flag(Flags::I) {
+            return;
+        }
+        self.interrupt(InterruptKind::Irq);
+        self.cycles = 7;
+    }
+
+    // Sample the interrupt lines at an instruction boundary: a latched NMI edge wins
+    // over the IRQ line. Returns true when an interrupt sequence started.
+    fn poll_interrupts(&mut self) -> bool {
+        if std::mem::take(&mut self.nmi_pending) {
+            self.nmi();
+            return true;
+        }
+        if self.irq_line && !self.get_flag(Flags::I) {
+            self.irq();
+            return true;
+        }
+        false
+    }
+
+    // Status byte as pushed on the stack: B is set by PHP/BRK, clear for IRQ/NMI
+    fn status_for_push(&self, brk: bool) -> u8 {
+        let mut status = self.status | Flags::U;
+        status.set(Flags::B, brk);
+        status.bits()
+    }
+
+    // Whole status register, e.g. pulled from the stack by PLP/RTI or restored from a
+    // save state: B is discarded and U forced, like `set_flag` does
+    pub fn set_status_byte(&mut self, value: u8) {
+        self.status = Flags::from_bits_truncate(value);
+        self.status.insert(Flags::U);
+        self.status.remove(Flags::B);
+    }
+
+    // Shared logic of the conditional branches: taking the branch costs an extra
+    // cycle, and one more if the target is on another page
+    fn branch(&mut self, condition: bool) {
+        if self.log_branches {
+            // branches are always 2 bytes long
+            let branch_addr = self.pc.wrapping_sub(2);
+            let (taken, not_taken) = self.branch_stats.entry(branch_addr).or_insert((0, 0));
+            if condition {
+                *taken += 1
+            } else {
+                *not_taken += 1
+            }
+        }
+
+        if condition {
+            self.cycles += 1;
+            self.addr_abs = self.pc.wrapping_add(self.addr_rel);
+
+            // pc already points past the operand, which is what the hardware compares to
+            if (self.addr_abs & 0xFF00) != (self.pc & 0xFF00) {
+                self.cycles += 1
+            }
+
+            self.pc = self.addr_abs
+        }
+    }
+
+    fn fetch(&mut self, opcodes: &Opcodes) -> u8 {
+        let Opcode { addr_mode, .. } = &opcodes[self.opcode];
         match addr_mode.kind() {
-            addr_modes::Kind::IMP => {}
+            addr_modes::Kind::IMP | addr_modes::Kind::ACC => {}
             _ => self.fetched = self.read(self.addr_abs),
         }
         self.fetched
     }
 
-    fn reset(&mut self) {
-        self.addr_abs = 0xFFFC;
-        let low = self.read(self.addr_abs) as u16;
-        let high = self.read(self.addr_abs + 1) as u16;
-
-        self.pc = (high << 8) | low;
-
+    // Power-up state, see https://www.nesdev.org/wiki/CPU_power_up_state
+    // The reset sequence then brings sp to 0xFD
+    fn power_on(&mut self) {
         self.a = 0x00;
         self.x = 0x00;
         self.y = 0x00;
-        self.sp = 0xFD;
+        self.sp = 0x00;
         self.status = Flags::U;
 
+        self.reset();
+    }
+
+    // A/X/Y are preserved, the stack pointer moves down by 3 as if 3 bytes were
+    // pushed (writes are inhibited) and interrupts are disabled
+    fn reset(&mut self) {
+        self.addr_abs = 0xFFFC;
+        self.pc = self.read_u16(self.addr_abs);
+
+        self.sp = self.sp.wrapping_sub(3);
+        self.set_flag(Flags::I, true);
+
         self.addr_rel = 0x0000;
         self.addr_abs = 0x0000;
         self.fetched = 0x00;
+        self.guard_violation = None;
+        self.jammed = false;
+        self.nmi_pending = false;
 
-        self.cycles = 8;
+        self.stall_cycles = 0;
+        self.cycles = RESET_CYCLES;
     }
 
     fn complete(&self) -> bool {
         self.cycles == 0
     }
+
+    fn halted(&self) -> bool {
+        self.guard_violation.is_some() || self.jammed
+    }
+
+    // The instruction at pc and the state before it runs, in the nestest.log format
+    // (without the PPU column):
+    // `C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:7`
+    // Memory operands show their effective address and the value stored there.
+    fn trace_line(&self, opcodes: &Opcodes) -> String {
+        let opcode = self.peek(self.pc);
+        let (name, kind) = match opcodes.get(opcode) {
+            None => ("???", Kind::IMP),
+            Some(Opcode {
+                name, addr_mode, ..
+            }) => (name.as_str(), addr_mode.kind()),
+        };
+
+        let bytes: Vec<u8> = (0..=kind.operand_len())
+            .map(|offset| self.peek(self.pc.wrapping_add(offset)))
+            .collect();
+        let low = bytes.get(1).copied().unwrap_or(0x00);
+        let word = ((bytes.get(2).copied().unwrap_or(0x00) as u16) << 8) | low as u16;
+        // pointers in the zero page wrap around it
+        let zp_word = |ptr: u8| {
+            let low = self.peek(ptr as u16) as u16;
+            let high = self.peek(ptr.wrapping_add(1) as u16) as u16;
+            (high << 8) | low
+        };
+
+        let operand = match kind {
+            Kind::IMP => String::new(),
+            Kind::ACC => "A".into(),
+            Kind::IMM => format!("#${:02X}", low),
+            Kind::ZP0 => format!("${:02X} = {:02X}", low, self.peek(low as u16)),
+            Kind::ZPX | Kind::ZPY => {
+                let (index, register) = match kind {
+                    Kind::ZPX => (self.x, "X"),
+                    _ => (self.y, "Y"),
+                };
+                let addr = low.wrapping_add(index);
+                let value = self.peek(addr as u16);
+                format!("${:02X},{} @ {:02X} = {:02X}", low, register, addr, value)
+            }
+            Kind::REL => {
+                let target = self.pc.wrapping_add(2).wrapping_add(low as i8 as u16);
+                format!("${:04X}", target)
+            }
+            Kind::ABS if name == "JMP" || name == "JSR" => format!("${:04X}", word),
+            Kind::ABS => format!("${:04X} = {:02X}", word, self.peek(word)),
+            Kind::ABX | Kind::ABY => {
+                let (index, register) = match kind {
+                    Kind::ABX => (self.x, "X"),
+                    _ => (self.y, "Y"),
+                };
+                let addr = word.wrapping_add(index as u16);
+                let value = self.peek(addr);
+                format!("${:04X},{} @ {:04X} = {:02X}", word, register, addr, value)
+            }
+            Kind::IND => {
+                // same page bug as the addressing mode
+                let high_addr = (word & 0xFF00) | (word.wrapping_add(1) & 0x00FF);
+                let target = ((self.peek(high_addr) as u16) << 8) | self.peek(word) as u16;
+                format!("(${:04X}) = {:04X}", word, target)
+            }
+            Kind::IZX => {
+                let ptr = low.wrapping_add(self.x);
+                let addr = zp_word(ptr);
+                let value = self.peek(addr);
+                format!(
+                    "(${:02X},X) @ {:02X} = {:04X} = {:02X}",
+                    low, ptr, addr, value
+                )
+            }
+            Kind::IZY => {
+                let base = zp_word(low);
+                let addr = base.wrapping_add(self.y as u16);
+                let value = self.peek(addr);
+                format!(
+                    "(${:02X}),Y = {:04X} @ {:04X} = {:02X}",
+                    low, base, addr, value
+                )
+            }
+        };
+
+        let raw: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let marker = if opcode_is_official(opcode) { ' ' } else { '*' };
+        let text = format!("{} {}", name, operand);
+        format!(
+            "{:04X}  {:<8} {}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+            self.pc,
+            raw.join(" "),
+            marker,
+            text.trim_end(),
+            self.a,
+            self.x,
+            self.y,
+            self.status_byte(),
+            self.sp,
+            self.clock_count
+        )
+    }
+}
+
+// The 151 documented opcodes, everything else is illegal (`*` in the reference table)
+#[rustfmt::skip]
+pub fn opcode_is_official(byte: u8) -> bool {
+    matches!(
+        byte,
+        0x00 | 0x01 | 0x05 | 0x06 | 0x08 | 0x09 | 0x0A | 0x0D | 0x0E
+            | 0x10 | 0x11 | 0x15 | 0x16 | 0x18 | 0x19 | 0x1D | 0x1E
+            | 0x20 | 0x21 | 0x24 | 0x25 | 0x26 | 0x28 | 0x29 | 0x2A | 0x2C | 0x2D | 0x2E
+            | 0x30 | 0x31 | 0x35 | 0x36 | 0x38 | 0x39 | 0x3D | 0x3E
+            | 0x40 | 0x41 | 0x45 | 0x46 | 0x48 | 0x49 | 0x4A | 0x4C | 0x4D | 0x4E
+            | 0x50 | 0x51 | 0x55 | 0x56 | 0x58 | 0x59 | 0x5D | 0x5E
+            | 0x60 | 0x61 | 0x65 | 0x66 | 0x68 | 0x69 | 0x6A | 0x6C | 0x6D | 0x6E
+            | 0x70 | 0x71 | 0x75 | 0x76 | 0x78 | 0x79 | 0x7D | 0x7E
+            | 0x81 | 0x84 | 0x85 | 0x86 | 0x88 | 0x8A | 0x8C | 0x8D | 0x8E
+            | 0x90 | 0x91 | 0x94 | 0x95 | 0x96 | 0x98 | 0x99 | 0x9A | 0x9D
+            | 0xA0 | 0xA1 | 0xA2 | 0xA4 | 0xA5 | 0xA6 | 0xA8 | 0xA9 | 0xAA | 0xAC | 0xAD | 0xAE
+            | 0xB0 | 0xB1 | 0xB4 | 0xB5 | 0xB6 | 0xB8 | 0xB9 | 0xBA | 0xBC | 0xBD | 0xBE
+            | 0xC0 | 0xC1 | 0xC4 | 0xC5 | 0xC6 | 0xC8 | 0xC9 | 0xCA | 0xCC | 0xCD | 0xCE
+            | 0xD0 | 0xD1 | 0xD5 | 0xD6 | 0xD8 | 0xD9 | 0xDD | 0xDE
+            | 0xE0 | 0xE1 | 0xE4 | 0xE5 | 0xE6 | 0xE8 | 0xE9 | 0xEA | 0xEC | 0xED | 0xEE
+            | 0xF0 | 0xF1 | 0xF5 | 0xF6 | 0xF8 | 0xF9 | 0xFD | 0xFE
+    )
 }
 
 pub struct Cpu {
     pub core: CpuCore,
-    opcodes: HashMap<u8, Opcode>,
+    opcodes: Opcodes,
+    // Labels shown in the disassembly
+    symbols: Symbols,
+    // Sets the clock rate
+    region: Region,
+    // Trace one instruction out of `trace_sampling`, see `Cpu::trace_sampled`
+    trace_sampling: usize,
 }
 
 macro_rules! add_opcode {
     ($opcodes:ident, $ndx: expr, $opcode: expr) => {
-        $opcodes.insert($ndx, $opcode)
+        $opcodes[$ndx] = $opcode
     };
 }
 
 impl Cpu {
-    pub fn bus(&self) -> Rc<RwLock<Bus>> {
-        self.core.bus.clone()
+    pub fn bus(&self) -> &Bus {
+        &self.core.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.core.bus
     }
 
     pub fn new(bus: Bus) -> Self {
-        let mut opcodes = HashMap::new();
+        let mut opcodes = Opcodes::new();
 
         /* opcode info mostly comes from
            https://www.nesdev.org/wiki/Visual6502wiki/6502_all_256_Opcodes
         */
 
-        add_opcode!(opcodes, 0x04, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0x0C, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0x14, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0x1A, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0x1C, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0x34, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0x3A, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0x3C, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0x44, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0x54, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0x5A, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0x5C, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0x64, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0x74, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0x7A, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0x7C, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0x80, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0x82, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0x89, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0xC2, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0xD4, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0xDA, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0xDC, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0xE2, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0xEA, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0xF4, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0xFA, opcode!(NOP, IMP, 0));
-        add_opcode!(opcodes, 0xFC, opcode!(NOP, IMP, 0));
+        add_opcode!(opcodes, 0x04, opcode!(NOP, ZP0, 3));
+        add_opcode!(opcodes, 0x0C, opcode!(NOP, ABS, 4));
+        add_opcode!(opcodes, 0x14, opcode!(NOP, ZPX, 4));
+        add_opcode!(opcodes, 0x1A, opcode!(NOP, IMP, 2));
+        add_opcode!(opcodes, 0x1C, opcode!(NOP, ABX, 4));
+        add_opcode!(opcodes, 0x34, opcode!(NOP, ZPX, 4));
+        add_opcode!(opcodes, 0x3A, opcode!(NOP, IMP, 2));
+        add_opcode!(opcodes, 0x3C, opcode!(NOP, ABX, 4));
+        add_opcode!(opcodes, 0x44, opcode!(NOP, ZP0, 3));
+        add_opcode!(opcodes, 0x54, opcode!(NOP, ZPX, 4));
+        add_opcode!(opcodes, 0x5A, opcode!(NOP, IMP, 2));
+        add_opcode!(opcodes, 0x5C, opcode!(NOP, ABX, 4));
+        add_opcode!(opcodes, 0x64, opcode!(NOP, ZP0, 3));
+        add_opcode!(opcodes, 0x74, opcode!(NOP, ZPX, 4));
+        add_opcode!(opcodes, 0x7A, opcode!(NOP, IMP, 2));
+        add_opcode!(opcodes, 0x7C, opcode!(NOP, ABX, 4));
+        add_opcode!(opcodes, 0x80, opcode!(NOP, IMM, 2));
+        add_opcode!(opcodes, 0x82, opcode!(NOP, IMM, 2));
+        add_opcode!(opcodes, 0x89, opcode!(NOP, IMM, 2));
+        add_opcode!(opcodes, 0xC2, opcode!(NOP, IMM, 2));
+        add_opcode!(opcodes, 0xD4, opcode!(NOP, ZPX, 4));
+        add_opcode!(opcodes, 0xDA, opcode!(NOP, IMP, 2));
+        add_opcode!(opcodes, 0xDC, opcode!(NOP, ABX, 4));
+        add_opcode!(opcodes, 0xE2, opcode!(NOP, IMM, 2));
+        add_opcode!(opcodes, 0xEA, opcode!(NOP, IMP, 2));
+        add_opcode!(opcodes, 0xF4, opcode!(NOP, ZPX, 4));
+        add_opcode!(opcodes, 0xFA, opcode!(NOP, IMP, 2));
+        add_opcode!(opcodes, 0xFC, opcode!(NOP, ABX, 4));
 
         add_opcode!(opcodes, 0xA1, opcode!(LDA, IZX, 6));
         add_opcode!(opcodes, 0xA5, opcode!(LDA, ZP0, 3));
@@ -254,16 +745,144 @@ impl Cpu {
         add_opcode!(opcodes, 0x79, opcode!(ADC, ABY, 4));
         add_opcode!(opcodes, 0x7D, opcode!(ADC, ABX, 4));
 
-        add_opcode!(opcodes, 0x18, opcode!(CLC, IMP, 2));
+        add_opcode!(opcodes, 0x21, opcode!(AND, IZX, 6));
+        add_opcode!(opcodes, 0x25, opcode!(AND, ZP0, 3));
+        add_opcode!(opcodes, 0x29, opcode!(AND, IMM, 2));
+        add_opcode!(opcodes, 0x2D, opcode!(AND, ABS, 4));
+        add_opcode!(opcodes, 0x31, opcode!(AND, IZY, 5));
+        add_opcode!(opcodes, 0x35, opcode!(AND, ZPX, 4));
+        add_opcode!(opcodes, 0x39, opcode!(AND, ABY, 4));
+        add_opcode!(opcodes, 0x3D, opcode!(AND, ABX, 4));
+
+        add_opcode!(opcodes, 0x24, opcode!(BIT, ZP0, 3));
+        add_opcode!(opcodes, 0x2C, opcode!(BIT, ABS, 4));
+
+        add_opcode!(opcodes, 0x41, opcode!(EOR, IZX, 6));
+        add_opcode!(opcodes, 0x45, opcode!(EOR, ZP0, 3));
+        add_opcode!(opcodes, 0x49, opcode!(EOR, IMM, 2));
+        add_opcode!(opcodes, 0x4D, opcode!(EOR, ABS, 4));
+        add_opcode!(opcodes, 0x51, opcode!(EOR, IZY, 5));
+        add_opcode!(opcodes, 0x55, opcode!(EOR, ZPX, 4));
+        add_opcode!(opcodes, 0x59, opcode!(EOR, ABY, 4));
+        add_opcode!(opcodes, 0x5D, opcode!(EOR, ABX, 4));
+
+        add_opcode!(opcodes, 0x01, opcode!(ORA, IZX, 6));
+        add_opcode!(opcodes, 0x05, opcode!(ORA, ZP0, 3));
+        add_opcode!(opcodes, 0x09, opcode!(ORA, IMM, 2));
+        add_opcode!(opcodes, 0x0D, opcode!(ORA, ABS, 4));
+        add_opcode!(opcodes, 0x11, opcode!(ORA, IZY, 5));
+        add_opcode!(opcodes, 0x15, opcode!(ORA, ZPX, 4));
+        add_opcode!(opcodes, 0x19, opcode!(ORA, ABY, 4));
+        add_opcode!(opcodes, 0x1D, opcode!(ORA, ABX, 4));
+
+        add_opcode!(opcodes, 0xE1, opcode!(SBC, IZX, 6));
+        add_opcode!(opcodes, 0xE5, opcode!(SBC, ZP0, 3));
+        add_opcode!(opcodes, 0xE9, opcode!(SBC, IMM, 2));
+        add_opcode!(opcodes, 0xED, opcode!(SBC, ABS, 4));
+        add_opcode!(opcodes, 0xF1, opcode!(SBC, IZY, 5));
+        add_opcode!(opcodes, 0xF5, opcode!(SBC, ZPX, 4));
+        add_opcode!(opcodes, 0xF9, opcode!(SBC, ABY, 4));
+        add_opcode!(opcodes, 0xFD, opcode!(SBC, ABX, 4));
+
+        add_opcode!(opcodes, 0xC1, opcode!(CMP, IZX, 6));
+        add_opcode!(opcodes, 0xC5, opcode!(CMP, ZP0, 3));
+        add_opcode!(opcodes, 0xC9, opcode!(CMP, IMM, 2));
+        add_opcode!(opcodes, 0xCD, opcode!(CMP, ABS, 4));
+        add_opcode!(opcodes, 0xD1, opcode!(CMP, IZY, 5));
+        add_opcode!(opcodes, 0xD5, opcode!(CMP, ZPX, 4));
+        add_opcode!(opcodes, 0xD9, opcode!(CMP, ABY, 4));
+        add_opcode!(opcodes, 0xDD, opcode!(CMP, ABX, 4));
+
+        add_opcode!(opcodes, 0xE0, opcode!(CPX, IMM, 2));
+        add_opcode!(opcodes, 0xE4, opcode!(CPX, ZP0, 3));
+        add_opcode!(opcodes, 0xEC, opcode!(CPX, ABS, 4));
+
+        add_opcode!(opcodes, 0xC0, opcode!(CPY, IMM, 2));
+        add_opcode!(opcodes, 0xC4, opcode!(CPY, ZP0, 3));
+        add_opcode!(opcodes, 0xCC, opcode!(CPY, ABS, 4));
 
+        add_opcode!(opcodes, 0x18, opcode!(CLC, IMP, 2));
+        add_opcode!(opcodes, 0x38, opcode!(SEC, IMP, 2));
+        add_opcode!(opcodes, 0x58, opcode!(CLI, IMP, 2));
+        add_opcode!(opcodes, 0x78, opcode!(SEI, IMP, 2));
+        add_opcode!(opcodes, 0xB8, opcode!(CLV, IMP, 2));
+        add_opcode!(opcodes, 0xD8, opcode!(CLD, IMP, 2));
+        add_opcode!(opcodes, 0xF8, opcode!(SED, IMP, 2));
+
+        add_opcode!(opcodes, 0xE6, opcode!(INC, ZP0, 5));
+        add_opcode!(opcodes, 0xEE, opcode!(INC, ABS, 6));
+        add_opcode!(opcodes, 0xF6, opcode!(INC, ZPX, 6));
+        add_opcode!(opcodes, 0xFE, opcode!(INC, ABX, 7));
+
+        add_opcode!(opcodes, 0xC6, opcode!(DEC, ZP0, 5));
+        add_opcode!(opcodes, 0xCE, opcode!(DEC, ABS, 6));
+        add_opcode!(opcodes, 0xD6, opcode!(DEC, ZPX, 6));
+        add_opcode!(opcodes, 0xDE, opcode!(DEC, ABX, 7));
+
+        add_opcode!(opcodes, 0x06, opcode!(ASL, ZP0, 5));
+        add_opcode!(opcodes, 0x0A, opcode!(ASL, ACC, 2));
+        add_opcode!(opcodes, 0x0E, opcode!(ASL, ABS, 6));
+        add_opcode!(opcodes, 0x16, opcode!(ASL, ZPX, 6));
+        add_opcode!(opcodes, 0x1E, opcode!(ASL, ABX, 7));
+
+        add_opcode!(opcodes, 0x46, opcode!(LSR, ZP0, 5));
+        add_opcode!(opcodes, 0x4A, opcode!(LSR, ACC, 2));
+        add_opcode!(opcodes, 0x4E, opcode!(LSR, ABS, 6));
+        add_opcode!(opcodes, 0x56, opcode!(LSR, ZPX, 6));
+        add_opcode!(opcodes, 0x5E, opcode!(LSR, ABX, 7));
+
+        add_opcode!(opcodes, 0x26, opcode!(ROL, ZP0, 5));
+        add_opcode!(opcodes, 0x2A, opcode!(ROL, ACC, 2));
+        add_opcode!(opcodes, 0x2E, opcode!(ROL, ABS, 6));
+        add_opcode!(opcodes, 0x36, opcode!(ROL, ZPX, 6));
+        add_opcode!(opcodes, 0x3E, opcode!(ROL, ABX, 7));
+
+        add_opcode!(opcodes, 0x66, opcode!(ROR, ZP0, 5));
+        add_opcode!(opcodes, 0x6A, opcode!(ROR, ACC, 2));
+        add_opcode!(opcodes, 0x6E, opcode!(ROR, ABS, 6));
+        add_opcode!(opcodes, 0x76, opcode!(ROR, ZPX, 6));
+        add_opcode!(opcodes, 0x7E, opcode!(ROR, ABX, 7));
+
+        add_opcode!(opcodes, 0xAA, opcode!(TAX, IMP, 2));
+        add_opcode!(opcodes, 0xA8, opcode!(TAY, IMP, 2));
+        add_opcode!(opcodes, 0xBA, opcode!(TSX, IMP, 2));
+        add_opcode!(opcodes, 0x8A, opcode!(TXA, IMP, 2));
+        add_opcode!(opcodes, 0x9A, opcode!(TXS, IMP, 2));
+        add_opcode!(opcodes, 0x98, opcode!(TYA, IMP, 2));
+
+        add_opcode!(opcodes, 0xE8, opcode!(INX, IMP, 2));
+        add_opcode!(opcodes, 0xC8, opcode!(INY, IMP, 2));
         add_opcode!(opcodes, 0xCA, opcode!(DEX, IMP, 2));
         add_opcode!(opcodes, 0x88, opcode!(DEY, IMP, 2));
 
-        add_opcode!(opcodes, 0xD0, opcode!(BNE, REL, 3));
+        add_opcode!(opcodes, 0x10, opcode!(BPL, REL, 2));
+        add_opcode!(opcodes, 0x30, opcode!(BMI, REL, 2));
+        add_opcode!(opcodes, 0x50, opcode!(BVC, REL, 2));
+        add_opcode!(opcodes, 0x70, opcode!(BVS, REL, 2));
+        add_opcode!(opcodes, 0x90, opcode!(BCC, REL, 2));
+        add_opcode!(opcodes, 0xB0, opcode!(BCS, REL, 2));
+        add_opcode!(opcodes, 0xD0, opcode!(BNE, REL, 2));
+        add_opcode!(opcodes, 0xF0, opcode!(BEQ, REL, 2));
+
+        add_opcode!(opcodes, 0x48, opcode!(PHA, IMP, 3));
+        add_opcode!(opcodes, 0x68, opcode!(PLA, IMP, 4));
+        add_opcode!(opcodes, 0x08, opcode!(PHP, IMP, 3));
+        add_opcode!(opcodes, 0x28, opcode!(PLP, IMP, 4));
+
+        add_opcode!(opcodes, 0x4C, opcode!(JMP, ABS, 3));
+        add_opcode!(opcodes, 0x20, opcode!(JSR, ABS, 6));
+        add_opcode!(opcodes, 0x60, opcode!(RTS, IMP, 6));
+
+        add_opcode!(opcodes, 0x00, opcode!(BRK, IMP, 7));
+        add_opcode!(opcodes, 0x40, opcode!(RTI, IMP, 6));
+        add_opcode!(opcodes, 0x6C, opcode!(JMP, IND, 5));
 
         Self {
             core: CpuCore::new(bus),
             opcodes,
+            symbols: Symbols::new(),
+            region: Region::default(),
+            trace_sampling: 1,
         }
     }
 
@@ -271,193 +890,2398 @@ impl Cpu {
         let Self { opcodes, core, .. } = self;
 
         if core.cycles == 0 {
-            let opcode = core.read(core.pc);
-            core.opcode = opcode;
+            if core.halted() {
+                return;
+            }
+            // the interrupt sequence takes the place of the next instruction
+            if core.poll_interrupts() {
+                core.cycles -= 1;
+                core.clock_count += 1;
+                return;
+            }
+
+            let instr_pc = core.pc;
+            if core.bus.is_guarded(instr_pc, GuardKind::Execute) {
+                core.guard_violation = Some(GuardViolation {
+                    pc: instr_pc,
+                    addr: instr_pc,
+                    kind: GuardKind::Execute,
+                });
+                return;
+            }
 
-            core.set_flag(Flags::U, true);
+            core.writes.clear();
 
-            core.pc += 1;
+            let opcode = core.read(core.pc);
+            core.opcode = opcode;
+            if opcodes.get(opcode).is_none() {
+                core.jammed = true;
+                return;
+            }
+            core.instruction_count += 1;
 
-            let xxx = opcode!(XXX, IMP, 0);
+            core.pc = core.pc.wrapping_add(1);
 
             let Opcode {
                 cycles,
                 addr_mode,
                 op,
                 ..
-            } = match opcodes.get(&opcode) {
-                None => &xxx,
-                Some(opcode) => opcode,
-            };
-            core.cycles = *cycles;
+            } = &opcodes[opcode];
+            core.cycles = *cycles + std::mem::take(&mut core.stall_cycles);
 
             let extra_cycle1 = addr_mode.run(core);
             let extra_cycle2 = op.run(opcodes, core);
 
             core.cycles += (extra_cycle1 & extra_cycle2) as usize;
 
-            // TODO:check if this is needed
-            // core.set_flag(Flags::U, true);
+            let guard_hit = core.bus.take_guard_hit();
+            if let Some((addr, kind)) = guard_hit {
+                core.guard_violation = Some(GuardViolation {
+                    pc: instr_pc,
+                    addr,
+                    kind,
+                });
+            }
         }
 
         core.cycles -= 1;
         core.clock_count += 1;
     }
 
-    pub fn reset(&mut self) {
-        self.core.reset()
+    pub fn power_on(&mut self) {
+        self.core.power_on()
     }
 
-    pub fn complete(&self) -> bool {
-        self.core.complete()
+    pub fn instruction_count(&self) -> usize {
+        self.core.instruction_count
     }
 
-    pub fn disassemble(&self, start_addr: u16, stop_addr: u16) -> BTreeMap<u16, String> {
-        let mut addr = start_addr;
-        let mut lines = BTreeMap::new();
+    pub fn total_cycles(&self) -> usize {
+        self.core.clock_count
+    }
 
-        let xxx = opcode!(XXX, IMP, 0);
+    pub fn region(&self) -> Region {
+        self.region
+    }
 
-        while addr <= stop_addr {
-            let line_addr = addr;
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
 
-            let mut line = format!("${:>04X}: ", addr);
-            let opcode = self.core.read(addr);
+    // Time the console takes to run `cycles` CPU cycles, in seconds
+    pub fn emulated_time(&self, cycles: u64) -> f64 {
+        cycles as f64 / self.region.cpu_frequency()
+    }
 
-            let Opcode {
-                name, addr_mode, ..
-            } = match self.opcodes.get(&opcode) {
-                None => &xxx,
-                Some(opcode) => opcode,
-            };
+    // Halt the CPU for `count` cycles, e.g. during OAM or DMC DMA. The stall is
+    // accounted to the next instruction.
+    pub fn add_stall_cycles(&mut self, count: usize) {
+        self.core.stall_cycles += count;
+    }
 
-            if addr == 0xFFFF {
+    // Run the next instruction, or only what is left of the current one when stopped
+    // in the middle of it (e.g. after `run_for` or the reset sequence). Returns the
+    // number of cycles it took.
+    pub fn step(&mut self) -> usize {
+        let start = self.core.clock_count;
+        loop {
+            self.clock();
+            if self.complete() {
                 break;
             }
-            addr += 1;
-            line = format!("{}{} ", line, name);
+        }
+        self.core.clock_count - start
+    }
 
-            match addr_mode.kind() {
-                addr_modes::Kind::IMP => line = format!("{} {{IMP}}", line),
-                addr_modes::Kind::IMM => {
-                    let value = self.core.read(addr);
-                    addr += 1;
-                    line = format!("{}#${:>02X} {{IMM}}", line, value)
-                }
-                addr_modes::Kind::ZP0 => {
-                    let low = self.core.read(addr);
-                    addr += 1;
-                    line = format!("{}${:>02X} {{ZP0}}", line, low)
-                }
-                addr_modes::Kind::ZPX => {
-                    let low = self.core.read(addr);
-                    addr += 1;
-                    line = format!("{}${:>02X}, X {{ZPX}}", line, low)
-                }
-                addr_modes::Kind::ZPY => {
-                    let low = self.core.read(addr);
-                    addr += 1;
-                    line = format!("{}${:>02X}, Y {{ZPY}}", line, low)
-                }
-                addr_modes::Kind::IZX => {
-                    let low = self.core.read(addr);
-                    addr += 1;
-                    line = format!("{}(${:>02X}, X) {{IZX}}", line, low)
-                }
-                addr_modes::Kind::IZY => {
-                    let low = self.core.read(addr);
-                    addr += 1;
-                    line = format!("{}(${:>02X}), Y {{IZY}}", line, low)
-                }
-                addr_modes::Kind::ABS => {
-                    let low = self.core.read(addr) as u16;
-                    addr += 1;
-                    let high = self.core.read(addr) as u16;
-                    addr += 1;
-                    line = format!("{}${:>04X} {{ABS}}", line, (high << 8) | low)
-                }
-                addr_modes::Kind::ABX => {
-                    let low = self.core.read(addr) as u16;
-                    addr += 1;
-                    let high = self.core.read(addr) as u16;
-                    addr += 1;
-                    line = format!("{}${:>04X}, X {{ABX}}", line, (high << 8) | low)
-                }
-                addr_modes::Kind::ABY => {
-                    let low = self.core.read(addr) as u16;
-                    addr += 1;
-                    let high = self.core.read(addr) as u16;
-                    addr += 1;
-                    line = format!("{}${:>04X}, Y {{ABY}}", line, (high << 8) | low)
-                }
-                addr_modes::Kind::IND => {
-                    let low = self.core.read(addr) as u16;
-                    addr += 1;
-                    let high = self.core.read(addr) as u16;
-                    addr += 1;
-                    line = format!("{}(${:>04X}) {{IND}}", line, (high << 8) | low)
-                }
-                addr_modes::Kind::REL => {
-                    let value = self.core.read(addr);
-                    addr += 1;
-                    line = format!(
-                        "{}${:>02X} [${:>04X}] {{REL}}",
-                        line,
-                        value,
-                        addr + value as u16
-                    )
-                }
+    // Run `count` whole instructions, returns the number of cycles it took
+    pub fn step_n(&mut self, count: usize) -> usize {
+        (0..count).map(|_| self.step()).sum()
+    }
+
+    // Run whole instructions until `flag` is `value`, checked between instructions.
+    // Gives up once `max_cycles` have elapsed. Returns the number of cycles it took.
+    pub fn run_until_flag(&mut self, flag: Flags, value: bool, max_cycles: usize) -> Option<usize> {
+        let start = self.core.clock_count;
+        loop {
+            if self.core.get_flag(flag) == value {
+                return Some(self.core.clock_count - start);
             }
-            lines.insert(line_addr, line);
+            if self.core.clock_count - start >= max_cycles || self.core.halted() {
+                return None;
+            }
+            self.step_n(1);
         }
-        lines
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    // Run exactly `cycles` clock ticks, possibly stopping in the middle of an instruction.
+    // Returns the number of cycles consumed, fewer if the CPU halted on a guard violation.
+    pub fn run_for(&mut self, cycles: usize) -> usize {
+        let start = self.core.clock_count;
+        for _ in 0..cycles {
+            if self.core.cycles == 0 && self.core.halted() {
+                break;
+            }
+            self.clock();
+        }
+        self.core.clock_count - start
+    }
 
-    #[test]
-    fn test_get_empty_flag() {
-        let cpu = CpuCore::new(Bus::new());
-        assert!(!cpu.get_flag(Flags::C));
-        assert!(!cpu.get_flag(Flags::Z));
-        assert!(!cpu.get_flag(Flags::I));
-        assert!(!cpu.get_flag(Flags::D));
-        assert!(!cpu.get_flag(Flags::B));
-        assert!(!cpu.get_flag(Flags::U));
-        assert!(!cpu.get_flag(Flags::V));
-        assert!(!cpu.get_flag(Flags::N));
+    pub fn reset(&mut self) {
+        self.core.reset()
     }
 
-    #[test]
-    fn test_set_get_flags() {
-        let mut cpu = CpuCore::new(Bus::new());
-        cpu.set_flag(Flags::C, true);
-        cpu.set_flag(Flags::V, true);
-        assert!(cpu.get_flag(Flags::C));
-        assert!(!cpu.get_flag(Flags::Z));
-        assert!(!cpu.get_flag(Flags::I));
-        assert!(!cpu.get_flag(Flags::D));
-        assert!(!cpu.get_flag(Flags::B));
-        assert!(!cpu.get_flag(Flags::U));
-        assert!(cpu.get_flag(Flags::V));
-        assert!(!cpu.get_flag(Flags::N));
+    pub fn nmi(&mut self) {
+        self.core.nmi()
     }
 
-    #[test]
-    fn test_set_get_flags2() {
-        let mut cpu = CpuCore::new(Bus::new());
-        cpu.set_flag(Flags::I | Flags::N, true);
-        assert!(!cpu.get_flag(Flags::C));
-        assert!(!cpu.get_flag(Flags::Z));
-        assert!(cpu.get_flag(Flags::I));
-        assert!(!cpu.get_flag(Flags::D));
-        assert!(!cpu.get_flag(Flags::B));
-        assert!(!cpu.get_flag(Flags::U));
-        assert!(!cpu.get_flag(Flags::V));
-        assert!(cpu.get_flag(Flags::N));
+    pub fn irq(&mut self) {
+        self.core.irq()
+    }
+
+    // Level of the shared IRQ line: while it is held and I is clear, an IRQ is taken
+    // at every instruction boundary
+    pub fn set_irq_line(&mut self, level: bool) {
+        self.core.irq_line = level;
+    }
+
+    // Level of the NMI line: only the low to high transition requests an NMI, taken at
+    // the next instruction boundary
+    pub fn set_nmi_line(&mut self, level: bool) {
+        if level && !self.core.nmi_line {
+            self.core.nmi_pending = true;
+        }
+        self.core.nmi_line = level;
+    }
+
+    pub fn complete(&self) -> bool {
+        self.core.complete()
+    }
+
+    // nestest.log style line for the next instruction, see `CpuCore::trace_line`
+    pub fn trace_line(&self) -> String {
+        self.core.trace_line(&self.opcodes)
+    }
+
+    // Keep one trace line every `n` instructions, for long runs. 0 and 1 trace them all.
+    pub fn set_trace_sampling(&mut self, n: usize) {
+        self.trace_sampling = n.max(1);
+    }
+
+    // Whether the next instruction belongs in a sampled trace: the first one, then
+    // every `trace_sampling` instructions.
+    pub fn trace_sampled(&self) -> bool {
+        self.core
+            .instruction_count
+            .is_multiple_of(self.trace_sampling)
+    }
+
+    pub fn current_opcode(&self) -> u8 {
+        self.core.opcode()
+    }
+
+    pub fn current_mnemonic(&self) -> &str {
+        self.mnemonic(self.current_opcode())
+    }
+
+    // Mnemonic from the opcode table, ??? if the opcode isn't supported
+    pub fn mnemonic(&self, opcode: u8) -> &str {
+        match self.opcodes.get(opcode) {
+            None => "???",
+            Some(Opcode { name, .. }) => name,
+        }
+    }
+
+    pub fn set_cycle_accurate(&mut self, on_off: bool) {
+        self.core.cycle_accurate = on_off;
+    }
+
+    pub fn set_branch_logging(&mut self, on_off: bool) {
+        self.core.log_branches = on_off;
+    }
+
+    pub fn set_interrupt_logging(&mut self, on_off: bool) {
+        self.core.log_interrupts = on_off;
+    }
+
+    // Interrupts taken since the last call
+    pub fn take_interrupt_log(&mut self) -> Vec<InterruptEvent> {
+        std::mem::take(&mut self.core.interrupt_log)
+    }
+
+    // Registers, flags and counters, plus the bytes of `memory` read without side effects
+    pub fn snapshot(&self, memory: Option<RangeInclusive<u16>>) -> Snapshot {
+        let core = &self.core;
+        let flags = Flags::all()
+            .iter_names()
+            .map(|(name, flag)| (name, core.get_flag(flag)))
+            .collect();
+        Snapshot {
+            a: core.a,
+            x: core.x,
+            y: core.y,
+            sp: core.sp,
+            pc: core.pc,
+            p: core.status_byte(),
+            flags,
+            cycles: core.clock_count,
+            instructions: core.instruction_count,
+            jammed: core.jammed,
+            memory: memory.map(|range| MemoryDump {
+                start: *range.start(),
+                bytes: range.map(|addr| core.peek(addr)).collect(),
+            }),
+        }
+    }
+
+    // Memory writes of the last instruction, as (address, old value, new value)
+    pub fn last_writes(&self) -> &[(u16, u8, u8)] {
+        &self.core.writes
+    }
+
+    // Restore the memory overwritten by the last instruction. Registers are left untouched.
+    pub fn undo_last_writes(&mut self) {
+        for (addr, old, _) in self.core.writes.drain(..).rev() {
+            self.core.bus.write(addr, old);
+        }
+    }
+
+    // The CPU stops executing once a guard fired
+    pub fn guard_violation(&self) -> Option<&GuardViolation> {
+        self.core.guard_violation.as_ref()
+    }
+
+    // The CPU stops executing on an unknown opcode, pc is left on it
+    pub fn jammed(&self) -> bool {
+        self.core.jammed
+    }
+
+    // Jammed or stopped by a guard: clocks do nothing until the next reset
+    pub fn halted(&self) -> bool {
+        self.core.halted()
+    }
+
+    // Per branch instruction address: (taken, not taken) counts
+    pub fn branch_stats(&self) -> &HashMap<u16, (u64, u64)> {
+        &self.core.branch_stats
+    }
+
+    // Resolve the address the instruction at PC will access, without touching the CPU state.
+    // Mirrors the logic of the addressing modes. Returns None for modes with no memory operand.
+    pub fn effective_address(&self) -> Option<u16> {
+        let core = &self.core;
+        let Opcode { addr_mode, .. } = self.opcodes.get(core.peek(core.pc))?;
+
+        let operand = core.pc.wrapping_add(1);
+        let read_u16 = |low_addr: u16, high_addr: u16| {
+            let low = core.peek(low_addr) as u16;
+            let high = core.peek(high_addr) as u16;
+            (high << 8) | low
+        };
+
+        match addr_mode.kind() {
+            addr_modes::Kind::IMP
+            | addr_modes::Kind::ACC
+            | addr_modes::Kind::IMM
+            | addr_modes::Kind::REL => None,
+            addr_modes::Kind::ZP0 => Some(core.peek(operand) as u16),
+            addr_modes::Kind::ZPX => Some(core.peek(operand).wrapping_add(core.x) as u16),
+            addr_modes::Kind::ZPY => Some(core.peek(operand).wrapping_add(core.y) as u16),
+            addr_modes::Kind::ABS => Some(read_u16(operand, operand.wrapping_add(1))),
+            addr_modes::Kind::ABX => {
+                let base = read_u16(operand, operand.wrapping_add(1));
+                Some(base.wrapping_add(core.x as u16))
+            }
+            addr_modes::Kind::ABY => {
+                let base = read_u16(operand, operand.wrapping_add(1));
+                Some(base.wrapping_add(core.y as u16))
+            }
+            addr_modes::Kind::IND => {
+                let ptr = read_u16(operand, operand.wrapping_add(1));
+                // page boundary hardware bug
+                let high = if ptr & 0x00FF == 0x00FF {
+                    ptr & 0xFF00
+                } else {
+                    ptr + 1
+                };
+                Some(read_u16(ptr, high))
+            }
+            addr_modes::Kind::IZX => {
+                let ptr_x = core.peek(operand).wrapping_add(core.x);
+                Some(read_u16(ptr_x as u16, ptr_x.wrapping_add(1) as u16))
+            }
+            addr_modes::Kind::IZY => {
+                let ptr = core.peek(operand);
+                let base = read_u16(ptr as u16, ptr.wrapping_add(1) as u16);
+                Some(base.wrapping_add(core.y as u16))
+            }
+        }
+    }
+
+    // Addressing mode of a registered opcode
+    pub fn addressing_mode(&self, opcode: u8) -> Option<Kind> {
+        self.opcodes
+            .get(opcode)
+            .map(|Opcode { addr_mode, .. }| addr_mode.kind())
+    }
+
+    // Unknown opcodes decode as the 1 byte XXX placeholder
+    fn addr_mode_kind(&self, opcode: u8) -> Kind {
+        self.addressing_mode(opcode).unwrap_or(Kind::IMP)
+    }
+
+    // Raw decoding of the instruction at `addr`
+    fn decode(&self, addr: u16) -> Instruction {
+        let opcode = self.core.peek(addr);
+        let operand_len = self.addr_mode_kind(opcode).operand_len();
+        let low = self.core.peek(addr.wrapping_add(1)) as u16;
+        let high = self.core.peek(addr.wrapping_add(2)) as u16;
+        let operand = match operand_len {
+            0 => 0,
+            1 => low,
+            _ => (high << 8) | low,
+        };
+        Instruction {
+            addr,
+            opcode,
+            operand,
+            len: 1 + operand_len as u8,
+        }
+    }
+
+    // Decode the instructions from `start_addr` to `stop_addr` into `buf`, replacing its
+    // content. Nothing is allocated as long as `buf` has enough capacity.
+    pub fn disassemble_range_into(
+        &self,
+        start_addr: u16,
+        stop_addr: u16,
+        buf: &mut Vec<Instruction>,
+    ) {
+        buf.clear();
+        let mut addr = start_addr as u32;
+        while addr <= stop_addr as u32 {
+            let instruction = self.decode(addr as u16);
+            addr += instruction.len as u32;
+            buf.push(instruction);
+        }
+    }
+
+    // Load debugger labels from a FCEUX (.nl) or Mesen (.mlb) file, returns how many
+    // were added
+    pub fn load_symbols_file(
+        &mut self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<usize, String> {
+        let symbols = symbols::load(path.as_ref())?;
+        let count = symbols.len();
+        self.symbols.extend(symbols);
+        Ok(count)
+    }
+
+    pub fn symbol(&self, addr: u16) -> Option<&str> {
+        self.symbols.get(&addr).map(String::as_str)
+    }
+
+    // Base cycle count of an opcode, with the possible penalties: `4(+1)` when crossing a
+    // page costs one more cycle, `2(+1/+2)` for a branch taken, on the same or another page
+    pub fn cycle_annotation(&self, opcode: u8) -> Option<String> {
+        let Opcode { name, cycles, .. } = self.opcodes.get(opcode)?;
+        let penalty = match self.addr_mode_kind(opcode) {
+            addr_modes::Kind::REL => "(+1/+2)",
+            // only reads pay for the page cross, writes and read-modify-writes always
+            // take the long path
+            addr_modes::Kind::ABX | addr_modes::Kind::ABY | addr_modes::Kind::IZY => {
+                match name.as_str() {
+                    "ADC" | "AND" | "CMP" | "EOR" | "LDA" | "LDX" | "LDY" | "NOP" | "ORA"
+                    | "SBC" => "(+1)",
+                    _ => "",
+                }
+            }
+            _ => "",
+        };
+        Some(format!("{}{}", cycles, penalty))
+    }
+
+    // Fixed width listing meant for diffing: `ADDR: BB BB BB  MNEMONIC OPERAND`, one line
+    // per instruction, optionally followed by `; CYCLES`
+    pub fn disassemble_text(&self, start_addr: u16, stop_addr: u16, cycles: bool) -> String {
+        let mut instructions = Vec::new();
+        self.disassemble_range_into(start_addr, stop_addr, &mut instructions);
+
+        let mut text = String::new();
+        for Instruction {
+            addr,
+            opcode,
+            operand,
+            len,
+        } in instructions
+        {
+            let low = operand & 0x00FF;
+            let high = operand >> 8;
+            let bytes = match len {
+                1 => format!("{:>02X}", opcode),
+                2 => format!("{:>02X} {:>02X}", opcode, low),
+                _ => format!("{:>02X} {:>02X} {:>02X}", opcode, low, high),
+            };
+            let operand = match self.addr_mode_kind(opcode) {
+                addr_modes::Kind::IMP => String::new(),
+                addr_modes::Kind::ACC => String::from("A"),
+                addr_modes::Kind::IMM => format!("#${:>02X}", low),
+                addr_modes::Kind::ZP0 => format!("${:>02X}", low),
+                addr_modes::Kind::ZPX => format!("${:>02X},X", low),
+                addr_modes::Kind::ZPY => format!("${:>02X},Y", low),
+                addr_modes::Kind::IZX => format!("(${:>02X},X)", low),
+                addr_modes::Kind::IZY => format!("(${:>02X}),Y", low),
+                addr_modes::Kind::ABS => format!("${:>04X}", operand),
+                addr_modes::Kind::ABX => format!("${:>04X},X", operand),
+                addr_modes::Kind::ABY => format!("${:>04X},Y", operand),
+                addr_modes::Kind::IND => format!("(${:>04X})", operand),
+                addr_modes::Kind::REL => {
+                    let target = addr.wrapping_add(2).wrapping_add(low as u8 as i8 as u16);
+                    format!("${:>04X}", target)
+                }
+            };
+            let line = format!(
+                "{:>04X}: {:<8}  {} {}",
+                addr,
+                bytes,
+                self.mnemonic(opcode),
+                operand
+            );
+            let line = line.trim_end();
+            if let Some(label) = self.symbol(addr) {
+                text.push_str(&format!("{}:\n", label));
+            }
+            match self.cycle_annotation(opcode) {
+                Some(annotation) if cycles => {
+                    text.push_str(&format!("{:<30}; {}", line, annotation))
+                }
+                _ => text.push_str(line),
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    // One line per instruction from `start_addr` to `stop_addr`, keyed by address. The
+    // cursor is wider than an address so an instruction at the top of memory ends the
+    // listing instead of wrapping back to $0000; its operand bytes wrap like the CPU does.
+    pub fn disassemble(&self, start_addr: u16, stop_addr: u16) -> BTreeMap<u16, String> {
+        let mut addr = start_addr as u32;
+        let mut lines = BTreeMap::new();
+
+        while addr <= stop_addr as u32 {
+            let Instruction {
+                addr: line_addr,
+                opcode,
+                operand,
+                len,
+            } = self.decode(addr as u16);
+            addr += len as u32;
+
+            let low = operand & 0x00FF;
+            let operand = match self.addr_mode_kind(opcode) {
+                addr_modes::Kind::IMP => " {IMP}".to_string(),
+                addr_modes::Kind::ACC => "A {ACC}".to_string(),
+                addr_modes::Kind::IMM => format!("#${:>02X} {{IMM}}", low),
+                addr_modes::Kind::ZP0 => format!("${:>02X} {{ZP0}}", low),
+                addr_modes::Kind::ZPX => format!("${:>02X}, X {{ZPX}}", low),
+                addr_modes::Kind::ZPY => format!("${:>02X}, Y {{ZPY}}", low),
+                addr_modes::Kind::IZX => format!("(${:>02X}, X) {{IZX}}", low),
+                addr_modes::Kind::IZY => format!("(${:>02X}), Y {{IZY}}", low),
+                addr_modes::Kind::ABS => format!("${:>04X} {{ABS}}", operand),
+                addr_modes::Kind::ABX => format!("${:>04X}, X {{ABX}}", operand),
+                addr_modes::Kind::ABY => format!("${:>04X}, Y {{ABY}}", operand),
+                addr_modes::Kind::IND => format!("(${:>04X}) {{IND}}", operand),
+                addr_modes::Kind::REL => format!(
+                    "${:>02X} [${:>04X}] {{REL}}",
+                    low,
+                    // the offset is signed, relative to the next instruction
+                    line_addr
+                        .wrapping_add(2)
+                        .wrapping_add(low as u8 as i8 as u16)
+                ),
+            };
+            let line = format!(
+                "${:>04X}: {} {}",
+                line_addr, self.opcodes[opcode].name, operand
+            );
+            lines.insert(line_addr, line);
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Build a CPU with `program` loaded at 0x8000 and the reset vector pointing to it.
+    // The reset cycles are already consumed, so the next clock fetches the first opcode.
+    pub(super) fn cpu_with_program(program: &[u8]) -> Cpu {
+        let mut bus = Bus::new();
+        for (addr, byte) in (0x8000..).zip(program) {
+            bus.write(addr, *byte);
+        }
+        bus.write(0xFFFC, 0x00);
+        bus.write(0xFFFD, 0x80);
+
+        let mut cpu = Cpu::new(bus);
+        cpu.power_on();
+        cpu.step();
+        cpu
+    }
+
+    #[test]
+    fn test_get_empty_flag() {
+        let cpu = CpuCore::new(Bus::new());
+        assert!(!cpu.get_flag(Flags::C));
+        assert!(!cpu.get_flag(Flags::Z));
+        assert!(!cpu.get_flag(Flags::I));
+        assert!(!cpu.get_flag(Flags::D));
+        assert!(!cpu.get_flag(Flags::B));
+        assert!(cpu.get_flag(Flags::U));
+        assert!(!cpu.get_flag(Flags::V));
+        assert!(!cpu.get_flag(Flags::N));
+    }
+
+    #[test]
+    fn test_set_get_flags() {
+        let mut cpu = CpuCore::new(Bus::new());
+        cpu.set_flag(Flags::C, true);
+        cpu.set_flag(Flags::V, true);
+        assert!(cpu.get_flag(Flags::C));
+        assert!(!cpu.get_flag(Flags::Z));
+        assert!(!cpu.get_flag(Flags::I));
+        assert!(!cpu.get_flag(Flags::D));
+        assert!(!cpu.get_flag(Flags::B));
+        assert!(cpu.get_flag(Flags::U));
+        assert!(cpu.get_flag(Flags::V));
+        assert!(!cpu.get_flag(Flags::N));
+    }
+
+    #[test]
+    fn test_set_get_flags2() {
+        let mut cpu = CpuCore::new(Bus::new());
+        cpu.set_flag(Flags::I | Flags::N, true);
+        assert!(!cpu.get_flag(Flags::C));
+        assert!(!cpu.get_flag(Flags::Z));
+        assert!(cpu.get_flag(Flags::I));
+        assert!(!cpu.get_flag(Flags::D));
+        assert!(!cpu.get_flag(Flags::B));
+        assert!(cpu.get_flag(Flags::U));
+        assert!(!cpu.get_flag(Flags::V));
+        assert!(cpu.get_flag(Flags::N));
+    }
+
+    #[test]
+    fn test_power_on_state() {
+        let mut cpu = CpuCore::new(Bus::new());
+        cpu.write(0xFFFC, 0x34);
+        cpu.write(0xFFFD, 0x12);
+        cpu.a = 0x11;
+        cpu.x = 0x22;
+        cpu.y = 0x33;
+        cpu.sp = 0x44;
+        cpu.set_flag(Flags::C | Flags::N, true);
+
+        cpu.power_on();
+        assert_eq!(cpu.pc, 0x1234);
+        assert_eq!((cpu.a, cpu.x, cpu.y), (0x00, 0x00, 0x00));
+        assert_eq!(cpu.sp, 0xFD);
+        assert_eq!(cpu.status, Flags::I | Flags::U);
+    }
+
+    #[test]
+    fn test_set_state() {
+        // LDA #$00 (sets Z)
+        let mut cpu = cpu_with_program(&[0xA9, 0x00]);
+        let core = &mut cpu.core;
+        core.set_a(0x12);
+        core.set_x(0x34);
+        core.set_y(0x56);
+        core.set_sp(0xAB);
+        core.set_pc(0x9000);
+        core.set_status_byte(0xC3);
+        assert_eq!(
+            (core.a, core.x, core.y, core.sp, core.pc),
+            (0x12, 0x34, 0x56, 0xAB, 0x9000)
+        );
+        // U reads as 1
+        assert_eq!(core.status_byte(), 0xE3);
+        assert!(core.get_flag(Flags::N) && core.get_flag(Flags::V) && core.get_flag(Flags::C));
+
+        for status in [0x00, 0x24, 0xEF, 0xFF] {
+            core.set_status_byte(status);
+            let round_trip = core.status_byte();
+            core.set_status_byte(round_trip);
+            assert_eq!(core.status_byte(), round_trip);
+            assert_eq!(round_trip, (status | Flags::U.bits()) & !Flags::B.bits());
+        }
+
+        core.set_pc(0x8000);
+        cpu.step();
+        assert!(cpu.core.get_flag(Flags::Z));
+        assert_eq!(cpu.core.a, 0x00);
+    }
+
+    #[test]
+    fn test_reset_state() {
+        let mut cpu = CpuCore::new(Bus::new());
+        cpu.write(0xFFFC, 0x34);
+        cpu.write(0xFFFD, 0x12);
+        cpu.power_on();
+
+        cpu.pc = 0x8765;
+        cpu.a = 0x11;
+        cpu.x = 0x22;
+        cpu.y = 0x33;
+        cpu.set_flag(Flags::I, false);
+        cpu.set_flag(Flags::C | Flags::N, true);
+
+        cpu.reset();
+        assert_eq!(cpu.pc, 0x1234);
+        assert_eq!((cpu.a, cpu.x, cpu.y), (0x11, 0x22, 0x33));
+        assert_eq!(cpu.sp, 0xFA);
+        assert_eq!(cpu.status, Flags::C | Flags::N | Flags::I | Flags::U);
+    }
+
+    #[test]
+    fn test_reset_cycles() {
+        let mut cpu = cpu_with_program(&[0xEA]);
+        assert_eq!(cpu.total_cycles(), RESET_CYCLES);
+        assert_eq!(cpu.instruction_count(), 0);
+
+        cpu.reset();
+        let mut clocks = 0;
+        while !cpu.complete() {
+            cpu.clock();
+            clocks += 1;
+        }
+        assert_eq!(clocks, 7);
+    }
+
+    #[test]
+    fn test_unused_flag_stays_set() {
+        let mut cpu = CpuCore::new(Bus::new());
+        cpu.set_flag(Flags::U, false);
+        assert!(cpu.get_flag(Flags::U));
+
+        cpu.set_flag(Flags::all(), false);
+        assert!(cpu.get_flag(Flags::U));
+        assert!(!cpu.get_flag(Flags::C));
+
+        cpu.set_flag(Flags::all(), true);
+        cpu.set_flag(Flags::C | Flags::U, false);
+        assert!(cpu.get_flag(Flags::U));
+        assert!(!cpu.get_flag(Flags::C));
+
+        cpu.reset();
+        assert!(cpu.get_flag(Flags::U));
+    }
+
+    #[test]
+    fn test_break_flag_only_on_stack() {
+        let mut cpu = CpuCore::new(Bus::new());
+        cpu.set_flag(Flags::B, true);
+        assert!(!cpu.get_flag(Flags::B));
+
+        cpu.set_flag(Flags::C, true);
+        assert_eq!(cpu.status_for_push(true), 0x31);
+        assert_eq!(cpu.status_for_push(false), 0x21);
+
+        // pulling a byte with B set doesn't leave it in the register
+        cpu.set_status_byte(0xFF);
+        assert!(!cpu.get_flag(Flags::B));
+        assert!(cpu.get_flag(Flags::U));
+        assert_eq!(cpu.status, Flags::all() - Flags::B);
+
+        cpu.set_status_byte(0x00);
+        assert_eq!(cpu.status, Flags::U);
+    }
+
+    #[test]
+    fn test_unused_flag_set_while_running() {
+        // CLC; LDA #$00; ADC #$80
+        let mut cpu = cpu_with_program(&[0x18, 0xA9, 0x00, 0x69, 0x80]);
+        for _ in 0..3 {
+            cpu.step();
+            assert!(cpu.core.get_flag(Flags::U));
+        }
+    }
+
+    #[test]
+    fn test_frozen_address_ignores_writes() {
+        // LDA #$42; STA $10
+        let mut cpu = cpu_with_program(&[0xA9, 0x42, 0x85, 0x10]);
+        cpu.bus_mut().freeze(0x0010, 0x99);
+
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.core.a, 0x42);
+        assert_eq!(cpu.core.read(0x0010), 0x99);
+
+        cpu.bus_mut().unfreeze(0x0010);
+        cpu.core.write(0x0010, 0x42);
+        assert_eq!(cpu.core.read(0x0010), 0x42);
+    }
+
+    #[test]
+    fn test_effective_address_izy() {
+        // LDY #$10; LDA ($80),Y
+        let mut cpu = cpu_with_program(&[0xA0, 0x10, 0xB1, 0x80]);
+        cpu.core.write(0x0080, 0x34);
+        cpu.core.write(0x0081, 0x12);
+
+        // LDY has an immediate operand: no effective address
+        assert_eq!(cpu.effective_address(), None);
+
+        cpu.step();
+        assert_eq!(cpu.effective_address(), Some(0x1244));
+
+        // resolving the address must not have moved the CPU
+        assert_eq!(cpu.core.pc, 0x8002);
+        assert_eq!(cpu.core.y, 0x10);
+    }
+
+    // Every addressing mode runs against a CpuCore and consumes as many operand
+    // bytes as its Kind says
+    #[test]
+    fn test_addr_modes_smoke() {
+        let modes: Vec<Box<dyn AddrMode>> = vec![
+            Box::new(addr_modes::IMP {}),
+            Box::new(addr_modes::ACC {}),
+            Box::new(addr_modes::IMM {}),
+            Box::new(addr_modes::ZP0 {}),
+            Box::new(addr_modes::ZPX {}),
+            Box::new(addr_modes::ZPY {}),
+            Box::new(addr_modes::REL {}),
+            Box::new(addr_modes::ABS {}),
+            Box::new(addr_modes::ABX {}),
+            Box::new(addr_modes::ABY {}),
+            Box::new(addr_modes::IND {}),
+            Box::new(addr_modes::IZX {}),
+            Box::new(addr_modes::IZY {}),
+        ];
+        for mode in modes {
+            let mut cpu = cpu_with_program(&[0x10, 0x20]);
+            mode.run(&mut cpu.core);
+            assert_eq!(
+                cpu.core.pc,
+                0x8000 + mode.kind().operand_len(),
+                "{:?}",
+                mode.kind()
+            );
+        }
+    }
+
+    #[test]
+    fn test_izy_wraps_in_zero_page() {
+        // LDY #$01; LDA ($FF),Y
+        let mut cpu = cpu_with_program(&[0xA0, 0x01, 0xB1, 0xFF]);
+        {
+            let bus = cpu.bus_mut();
+            bus.write(0x00FF, 0x33);
+            bus.write(0x0000, 0x12);
+            bus.write(0x0100, 0x56);
+            bus.write(0x1234, 0x42);
+            bus.write(0x5634, 0x99);
+        }
+
+        cpu.step_n(1);
+        assert_eq!(cpu.effective_address(), Some(0x1234));
+        assert_eq!(cpu.step_n(1), 5);
+        assert_eq!(cpu.core.a, 0x42);
+    }
+
+    #[test]
+    fn test_izx_wraps_in_zero_page() {
+        // LDX #$10; LDA ($F0,X); LDX #$0F; LDA ($F0,X)
+        let mut cpu = cpu_with_program(&[0xA2, 0x10, 0xA1, 0xF0, 0xA2, 0x0F, 0xA1, 0xF0]);
+        {
+            let bus = cpu.bus_mut();
+            // $F0 + $10 = $00
+            bus.write(0x0000, 0x34);
+            bus.write(0x0001, 0x12);
+            bus.write(0x1234, 0x42);
+            // $F0 + $0F = $FF, high byte from $00 and not $0100
+            bus.write(0x00FF, 0x35);
+            bus.write(0x0100, 0x56);
+            bus.write(0x3435, 0x24);
+            bus.write(0x5635, 0x99);
+        }
+
+        cpu.step_n(1);
+        assert_eq!(cpu.effective_address(), Some(0x1234));
+        cpu.step_n(1);
+        assert_eq!(cpu.core.a, 0x42);
+
+        cpu.step_n(1);
+        assert_eq!(cpu.effective_address(), Some(0x3435));
+        cpu.step_n(1);
+        assert_eq!(cpu.core.a, 0x24);
+    }
+
+    #[test]
+    fn test_effective_address_has_no_side_effects() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        // LDY #$01; LDA ($10),Y
+        let mut cpu = cpu_with_program(&[0xA0, 0x01, 0xB1, 0x10]);
+        cpu.bus_mut().write(0x0010, 0x34);
+        cpu.bus_mut().write(0x0011, 0x12);
+        cpu.step_n(1);
+
+        let reads = Rc::new(RefCell::new(Vec::new()));
+        let log = reads.clone();
+        cpu.bus_mut()
+            .set_read_callback(Some(Box::new(move |addr| log.borrow_mut().push(addr))));
+
+        assert_eq!(cpu.effective_address(), Some(0x1235));
+        assert!(reads.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_page_cross_dummy_read() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        // LDX #$20; LDA $12F0,X
+        let mut cpu = cpu_with_program(&[0xA2, 0x20, 0xBD, 0xF0, 0x12]);
+        cpu.set_cycle_accurate(true);
+
+        let reads = Rc::new(RefCell::new(Vec::new()));
+        let log = reads.clone();
+        cpu.bus_mut()
+            .set_read_callback(Some(Box::new(move |addr| log.borrow_mut().push(addr))));
+
+        cpu.step();
+        cpu.step();
+
+        let data_reads: Vec<u16> = reads
+            .borrow()
+            .iter()
+            .copied()
+            .filter(|addr| *addr < 0x8000)
+            .collect();
+        assert_eq!(data_reads, vec![0x1210, 0x1310]);
+    }
+
+    #[test]
+    fn test_branch_stats() {
+        // LDX #$03; DEX; BNE -3
+        let mut cpu = cpu_with_program(&[0xA2, 0x03, 0xCA, 0xD0, 0xFD]);
+        cpu.set_branch_logging(true);
+
+        for _ in 0..7 {
+            cpu.step();
+        }
+        assert_eq!(cpu.core.x, 0);
+        assert_eq!(cpu.core.pc, 0x8005);
+        assert_eq!(cpu.branch_stats().len(), 1);
+        assert_eq!(cpu.branch_stats()[&0x8003], (2, 1));
+    }
+
+    #[test]
+    fn test_branch_timing() {
+        // BNE at `addr` with `offset`, returns the cycles it took and the new pc
+        let run_branch = |addr: u16, offset: u8, zero: bool| {
+            let mut cpu = cpu_with_program(&[]);
+            cpu.bus_mut().write(addr, 0xD0);
+            cpu.bus_mut().write(addr.wrapping_add(1), offset);
+            cpu.core.pc = addr;
+            cpu.core.set_flag(Flags::Z, zero);
+            (cpu.step_n(1), cpu.core.pc)
+        };
+
+        // not taken
+        assert_eq!(run_branch(0x8010, 0x10, true), (2, 0x8012));
+        // taken, same page
+        assert_eq!(run_branch(0x8010, 0x10, false), (3, 0x8022));
+        // taken, next page
+        assert_eq!(run_branch(0x80F0, 0x10, false), (4, 0x8102));
+        // taken backward, previous page
+        assert_eq!(run_branch(0x8100, 0xF0, false), (4, 0x80F2));
+
+        // the branch sits on page $80 but the operand is the last byte of it: the
+        // reference is the pc after the operand, so $8102 is on the same page...
+        assert_eq!(run_branch(0x80FE, 0x02, false), (3, 0x8102));
+        // ... and $80FD is not
+        assert_eq!(run_branch(0x80FE, 0xFD, false), (4, 0x80FD));
+    }
+
+    #[test]
+    fn test_zero_page_index_wraps() {
+        // LDX #$FF; LDA $80,X; LDY #$01; LDX $FF,Y
+        let mut cpu = cpu_with_program(&[0xA2, 0xFF, 0xB5, 0x80, 0xA0, 0x01, 0xB6, 0xFF]);
+        cpu.bus_mut().write(0x007F, 0x42);
+        cpu.bus_mut().write(0x0000, 0x24);
+        cpu.step_n(2);
+        assert_eq!(cpu.core.a, 0x42);
+        cpu.step_n(2);
+        assert_eq!(cpu.core.x, 0x24);
+    }
+
+    #[test]
+    fn test_pc_wraps_at_top_of_memory() {
+        // LDA #$42 with the opcode at $FFFF and its operand at $0000
+        let mut cpu = cpu_with_program(&[]);
+        cpu.bus_mut().write(0xFFFF, 0xA9);
+        cpu.bus_mut().write(0x0000, 0x42);
+        cpu.core.pc = 0xFFFF;
+        cpu.step_n(1);
+        assert_eq!(cpu.core.a, 0x42);
+        assert_eq!(cpu.core.pc, 0x0001);
+
+        // LDX $1234 with the address split across $FFFF/$0000
+        let mut cpu = cpu_with_program(&[]);
+        for (addr, byte) in [
+            (0xFFFE, 0xAE),
+            (0xFFFF, 0x34),
+            (0x0000, 0x12),
+            (0x1234, 0x24),
+        ] {
+            cpu.bus_mut().write(addr, byte);
+        }
+        cpu.core.pc = 0xFFFE;
+        cpu.step_n(1);
+        assert_eq!(cpu.core.x, 0x24);
+        assert_eq!(cpu.core.pc, 0x0001);
+    }
+
+    #[test]
+    fn test_relative_offset_extremes() {
+        for (offset, target) in [(0x7F, 0x8091), (0x80, 0x7F92)] {
+            let mut cpu = cpu_with_program(&[]);
+            cpu.bus_mut().write(0x8010, 0xD0);
+            cpu.bus_mut().write(0x8011, offset);
+            cpu.core.pc = 0x8010;
+            cpu.step_n(1);
+            assert_eq!(cpu.core.pc, target);
+        }
+    }
+
+    #[test]
+    fn test_unknown_opcode_jams() {
+        // LDX #$01; KIL
+        let mut cpu = cpu_with_program(&[0xA2, 0x01, 0x02, 0xE8]);
+        assert_eq!(cpu.step(), 2);
+        assert!(!cpu.jammed());
+
+        // nothing runs: no cycle, no instruction, pc stays on the opcode
+        assert_eq!(cpu.step(), 0);
+        assert!(cpu.jammed() && cpu.halted());
+        assert!(cpu.complete());
+        assert_eq!(cpu.step_n(10), 0);
+        assert_eq!(cpu.run_for(10), 0);
+        assert_eq!(cpu.run_until_flag(Flags::Z, true, 100), None);
+        assert_eq!((cpu.core.pc, cpu.core.x), (0x8002, 0x01));
+        assert_eq!(cpu.instruction_count(), 1);
+        assert_eq!(cpu.current_opcode(), 0x02);
+
+        // only a reset gets it going again
+        cpu.reset();
+        assert!(!cpu.jammed());
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.core.x, 0x01);
+    }
+
+    #[test]
+    fn test_branch_family() {
+        // (opcode, flag, taken when the flag is)
+        let branches = [
+            (0x10, Flags::N, false),
+            (0x30, Flags::N, true),
+            (0x50, Flags::V, false),
+            (0x70, Flags::V, true),
+            (0x90, Flags::C, false),
+            (0xB0, Flags::C, true),
+            (0xD0, Flags::Z, false),
+            (0xF0, Flags::Z, true),
+        ];
+        for (opcode, flag, taken_when) in branches {
+            // (offset, flag value, expected cycles and pc) from $8010
+            let cases = [
+                (0x10, !taken_when, 2, 0x8012),
+                (0x10, taken_when, 3, 0x8022),
+                (0xF0, taken_when, 3, 0x8002),
+                (0xE0, taken_when, 4, 0x7FF2),
+            ];
+            for (offset, value, cycles, pc) in cases {
+                let mut cpu = cpu_with_program(&[]);
+                cpu.bus_mut().write(0x8010, opcode);
+                cpu.bus_mut().write(0x8011, offset);
+                cpu.core.pc = 0x8010;
+                cpu.core.set_flag(flag, value);
+
+                let context = format!(
+                    "${:>02X} {:?}={} offset ${:>02X}",
+                    opcode, flag, value, offset
+                );
+                assert_eq!(cpu.step_n(1), cycles, "{}", context);
+                assert_eq!(cpu.core.pc, pc, "{}", context);
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_guard() {
+        // LDA #$42; STA $10; LDA #$00
+        let mut cpu = cpu_with_program(&[0xA9, 0x42, 0x85, 0x10, 0xA9, 0x00]);
+        cpu.bus_mut().add_guard(0x0000..=0x00FF, GuardKind::Write);
+
+        cpu.step();
+        assert_eq!(cpu.guard_violation(), None);
+        cpu.step();
+        assert_eq!(
+            cpu.guard_violation(),
+            Some(&GuardViolation {
+                pc: 0x8002,
+                addr: 0x0010,
+                kind: GuardKind::Write,
+            })
+        );
+
+        // halted: the last LDA never runs
+        cpu.step();
+        assert_eq!(cpu.core.pc, 0x8004);
+        assert_eq!(cpu.core.a, 0x42);
+    }
+
+    #[test]
+    fn test_execute_guard() {
+        // LDA #$42; LDA #$00
+        let mut cpu = cpu_with_program(&[0xA9, 0x42, 0xA9, 0x00]);
+        cpu.bus_mut().add_guard(0x8002..=0x8002, GuardKind::Execute);
+
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.core.a, 0x42);
+        assert_eq!(
+            cpu.guard_violation(),
+            Some(&GuardViolation {
+                pc: 0x8002,
+                addr: 0x8002,
+                kind: GuardKind::Execute,
+            })
+        );
+    }
+
+    #[test]
+    fn test_last_fetched_opcode() {
+        // LDX #$01; DEX
+        let mut cpu = cpu_with_program(&[0xA2, 0x01, 0xCA]);
+        cpu.step();
+        assert_eq!(cpu.core.opcode(), 0xA2);
+        assert_eq!(cpu.mnemonic(cpu.core.opcode()), "LDX");
+        cpu.step();
+        assert_eq!(cpu.core.opcode(), 0xCA);
+        assert_eq!(cpu.mnemonic(0x02), "???");
+    }
+
+    #[test]
+    fn test_current_opcode() {
+        // LDA #$00; BNE +0
+        let mut cpu = cpu_with_program(&[0xA9, 0x00, 0xD0, 0x00]);
+        cpu.step();
+        assert_eq!(cpu.current_opcode(), 0xA9);
+        assert_eq!(cpu.current_mnemonic(), "LDA");
+        cpu.step();
+        assert_eq!(cpu.current_opcode(), 0xD0);
+        assert_eq!(cpu.current_mnemonic(), "BNE");
+    }
+
+    #[test]
+    fn test_last_writes() {
+        // LDA #$42; STA $10; LDA #$43; STA $10
+        let mut cpu = cpu_with_program(&[0xA9, 0x42, 0x85, 0x10, 0xA9, 0x43, 0x85, 0x10]);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.last_writes(), &[(0x0010, 0x00, 0x42)]);
+
+        cpu.step();
+        assert!(cpu.last_writes().is_empty());
+
+        cpu.step();
+        assert_eq!(cpu.last_writes(), &[(0x0010, 0x42, 0x43)]);
+
+        cpu.undo_last_writes();
+        assert_eq!(cpu.core.read(0x0010), 0x42);
+        assert!(cpu.last_writes().is_empty());
+    }
+
+    #[test]
+    fn test_disassemble_text_columns() {
+        // LDX #$0A; STX $0200; DEX; BNE -4; LDA ($80),Y; JAM (unsupported)
+        let cpu = cpu_with_program(&[
+            0xA2, 0x0A, 0x8E, 0x00, 0x02, 0xCA, 0xD0, 0xFC, 0xB1, 0x80, 0x02,
+        ]);
+        let text = cpu.disassemble_text(0x8000, 0x800A, false);
+        let expected = [
+            "8000: A2 0A     LDX #$0A",
+            "8002: 8E 00 02  STX $0200",
+            "8005: CA        DEX",
+            "8006: D0 FC     BNE $8004",
+            "8008: B1 80     LDA ($80),Y",
+            "800A: 02        ???",
+        ];
+        assert_eq!(text.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_disassemble_cycles() {
+        // LDX #$0A; STA $0200,X; LDA ($80),Y; BNE -4; JAM (unsupported)
+        let cpu = cpu_with_program(&[0xA2, 0x0A, 0x9D, 0x00, 0x02, 0xB1, 0x80, 0xD0, 0xFC, 0x02]);
+        let text = cpu.disassemble_text(0x8000, 0x8009, true);
+        let expected = [
+            "8000: A2 0A     LDX #$0A      ; 2",
+            "8002: 9D 00 02  STA $0200,X   ; 5",
+            "8005: B1 80     LDA ($80),Y   ; 5(+1)",
+            "8007: D0 FC     BNE $8005     ; 2(+1/+2)",
+            "8009: 02        ???",
+        ];
+        assert_eq!(text.lines().collect::<Vec<_>>(), expected);
+        assert_eq!(cpu.cycle_annotation(0xBD).as_deref(), Some("4(+1)"));
+        assert_eq!(cpu.cycle_annotation(0x02), None);
+    }
+
+    #[test]
+    fn test_load_symbols_file() {
+        // LDX #$0A; DEX; BNE -3
+        let mut cpu = cpu_with_program(&[0xA2, 0x0A, 0xCA, 0xD0, 0xFD]);
+        let dir = std::env::temp_dir().join(format!("yane2-symbols-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let nl = dir.join("test.nes.0.nl");
+        let mlb = dir.join("test.mlb");
+        std::fs::write(&nl, "$8000#Start#\n").unwrap();
+        std::fs::write(&mlb, "P:0002:Loop\nR:0010:counter\n").unwrap();
+
+        assert_eq!(cpu.load_symbols_file(&nl), Ok(1));
+        assert_eq!(cpu.load_symbols_file(&mlb), Ok(2));
+        assert!(cpu.load_symbols_file(dir.join("test.sym")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(cpu.symbol(0x0010), Some("counter"));
+        let expected = [
+            "Start:",
+            "8000: A2 0A     LDX #$0A",
+            "Loop:",
+            "8002: CA        DEX",
+            "8003: D0 FD     BNE $8002",
+        ];
+        let text = cpu.disassemble_text(0x8000, 0x8004, false);
+        assert_eq!(text.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_disassemble_branch_target() {
+        // LDX #$03; DEX; NOP; BNE -5; BNE +2
+        let cpu = cpu_with_program(&[0xA2, 0x03, 0xCA, 0xEA, 0xD0, 0xFB, 0xD0, 0x02]);
+        let lines = cpu.disassemble(0x8000, 0x8007);
+        assert_eq!(lines[&0x8004], "$8004: BNE $FB [$8001] {REL}");
+        assert_eq!(lines[&0x8006], "$8006: BNE $02 [$800A] {REL}");
+    }
+
+    #[test]
+    fn test_disassemble_top_of_memory() {
+        let mut cpu = cpu_with_program(&[]);
+        // LDA #$42 with its operand in the last byte, then NOP alone at $FFFF
+        cpu.bus_mut().write(0xFFFE, 0xA9);
+        cpu.bus_mut().write(0xFFFF, 0x42);
+        let lines = cpu.disassemble(0xFFFE, 0xFFFF);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[&0xFFFE], "$FFFE: LDA #$42 {IMM}");
+
+        cpu.bus_mut().write(0xFFFF, 0xEA);
+        let lines = cpu.disassemble(0xFFFD, 0xFFFF);
+        assert_eq!(lines.keys().last(), Some(&0xFFFF));
+        assert_eq!(lines[&0xFFFF], "$FFFF: NOP  {IMP}");
+
+        // the operand wraps to $0000-$0001, the listing doesn't
+        cpu.bus_mut().write(0xFFFF, 0xAD);
+        cpu.bus_mut().write(0x0000, 0x34);
+        cpu.bus_mut().write(0x0001, 0x12);
+        let lines = cpu.disassemble(0xFFFF, 0xFFFF);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[&0xFFFF], "$FFFF: LDA $1234 {ABS}");
+    }
+
+    #[test]
+    fn test_trace_sampling() {
+        // NOP; JMP $8000
+        let mut cpu = cpu_with_program(&[0xEA, 0x4C, 0x00, 0x80]);
+        cpu.set_trace_sampling(10);
+
+        let mut lines = Vec::new();
+        for _ in 0..1000 {
+            if cpu.trace_sampled() {
+                lines.push(cpu.trace_line());
+            }
+            cpu.step();
+        }
+        assert_eq!(lines.len(), 100);
+        assert!(lines[0].starts_with("8000  EA"));
+
+        cpu.set_trace_sampling(0);
+        assert!(cpu.trace_sampled());
+    }
+
+    #[test]
+    fn test_disassemble_has_no_side_effects() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        // LDA $1234; STA $0200
+        let mut cpu = cpu_with_program(&[0xAD, 0x34, 0x12, 0x8D, 0x00, 0x02]);
+        let reads = Rc::new(RefCell::new(Vec::new()));
+        let log = reads.clone();
+        cpu.bus_mut()
+            .set_read_callback(Some(Box::new(move |addr| log.borrow_mut().push(addr))));
+
+        assert_eq!(cpu.disassemble(0x8000, 0x8005).len(), 2);
+        assert!(reads.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_disassemble_accumulator() {
+        // ASL A; DEX
+        let cpu = cpu_with_program(&[0x0A, 0xCA]);
+
+        let lines = cpu.disassemble(0x8000, 0x8001);
+        assert_eq!(lines[&0x8000], "$8000: ASL A {ACC}");
+        assert_eq!(lines[&0x8001], "$8001: DEX  {IMP}");
+
+        let instruction = cpu.decode(0x8000);
+        assert_eq!(instruction.len, 1);
+        assert_eq!(
+            cpu.disassemble_text(0x8000, 0x8001, false),
+            "8000: 0A        ASL A\n8001: CA        DEX\n"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_range_into_reuses_buffer() {
+        // LDX #$0A; STX $0200; DEX; BNE -4
+        let cpu = cpu_with_program(&[0xA2, 0x0A, 0x8E, 0x00, 0x02, 0xCA, 0xD0, 0xFC]);
+        let mut buf = Vec::with_capacity(16);
+        let ptr = buf.as_ptr();
+
+        for _ in 0..3 {
+            cpu.disassemble_range_into(0x8000, 0x8007, &mut buf);
+            assert_eq!(buf.as_ptr(), ptr);
+            assert_eq!(buf.capacity(), 16);
+        }
+        assert_eq!(
+            buf,
+            vec![
+                Instruction {
+                    addr: 0x8000,
+                    opcode: 0xA2,
+                    operand: 0x0A,
+                    len: 2
+                },
+                Instruction {
+                    addr: 0x8002,
+                    opcode: 0x8E,
+                    operand: 0x0200,
+                    len: 3
+                },
+                Instruction {
+                    addr: 0x8005,
+                    opcode: 0xCA,
+                    operand: 0,
+                    len: 1
+                },
+                Instruction {
+                    addr: 0x8006,
+                    opcode: 0xD0,
+                    operand: 0xFC,
+                    len: 2
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_owned_bus() {
+        // LDA $0200; STA $0201
+        let mut cpu = cpu_with_program(&[0xAD, 0x00, 0x02, 0x8D, 0x01, 0x02]);
+
+        // written from outside, read by the CPU
+        cpu.bus_mut().write(0x0200, 0x42);
+        cpu.step();
+        assert_eq!(cpu.core.a, 0x42);
+
+        // written by the CPU, seen from outside
+        cpu.step();
+        assert_eq!(cpu.bus().peek(0x0201), 0x42);
+        assert_eq!(cpu.last_writes(), &[(0x0201, 0x00, 0x42)]);
+        cpu.undo_last_writes();
+        assert_eq!(cpu.bus().peek(0x0201), 0x00);
+    }
+
+    #[test]
+    fn test_step() {
+        // LDX #$0A; STX $0000; LDA ($10),Y
+        let mut cpu = cpu_with_program(&[0xA2, 0x0A, 0x8E, 0x00, 0x00, 0xB1, 0x10]);
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.core.x, 0x0A);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.instruction_count(), 2);
+
+        // stopped after 2 of the 5 cycles of LDA ($10),Y: step finishes it
+        assert_eq!(cpu.run_for(2), 2);
+        assert!(!cpu.complete());
+        assert_eq!(cpu.step(), 3);
+        assert!(cpu.complete());
+        assert_eq!(cpu.instruction_count(), 3);
+        assert_eq!(cpu.core.pc, 0x8007);
+
+        // right after power on, the reset sequence
+        cpu.power_on();
+        assert_eq!(cpu.step(), 7);
+        assert_eq!(cpu.instruction_count(), 3);
+        assert_eq!(cpu.core.pc, 0x8000);
+    }
+
+    #[test]
+    fn test_step_n() {
+        // LDX #$0A; STX $0000; LDX #$03; DEX
+        let mut cpu = cpu_with_program(&[0xA2, 0x0A, 0x8E, 0x00, 0x00, 0xA2, 0x03, 0xCA]);
+        let start = cpu.total_cycles();
+
+        assert_eq!(cpu.step_n(3), 2 + 4 + 2);
+        assert_eq!(cpu.core.pc, 0x8007);
+        assert_eq!(cpu.total_cycles() - start, 8);
+        assert_eq!(cpu.core.x, 0x03);
+
+        assert_eq!(cpu.step_n(0), 0);
+        assert_eq!(cpu.core.pc, 0x8007);
+    }
+
+    #[test]
+    fn test_run_for() {
+        // LDA ($80),Y; DEX
+        let mut cpu = cpu_with_program(&[0xB1, 0x80, 0xCA]);
+        let start = cpu.total_cycles();
+
+        assert_eq!(cpu.run_for(3), 3);
+        assert_eq!(cpu.total_cycles() - start, 3);
+        assert!(!cpu.complete());
+        assert_eq!(cpu.instruction_count(), 1);
+
+        assert_eq!(cpu.run_for(2), 2);
+        assert!(cpu.complete());
+        assert_eq!(cpu.instruction_count(), 1);
+        assert_eq!(cpu.run_for(0), 0);
+    }
+
+    #[test]
+    fn test_run_until_flag() {
+        // LDA #$07; EOR #$01; EOR #$02; EOR #$04; LDX #$02; BNE -2 (forever)
+        let mut cpu = cpu_with_program(&[
+            0xA9, 0x07, 0x49, 0x01, 0x49, 0x02, 0x49, 0x04, 0xA2, 0x02, 0xD0, 0xFE,
+        ]);
+        assert_eq!(cpu.run_until_flag(Flags::Z, true, 100), Some(8));
+        assert_eq!(cpu.core.a, 0x00);
+        assert_eq!(cpu.core.pc, 0x8008);
+        // already there
+        assert_eq!(cpu.run_until_flag(Flags::Z, true, 100), Some(0));
+
+        assert_eq!(cpu.run_until_flag(Flags::Z, false, 100), Some(2));
+        assert_eq!(cpu.core.x, 0x02);
+
+        // the loop never touches carry
+        assert_eq!(cpu.run_until_flag(Flags::C, true, 20), None);
+    }
+
+    #[test]
+    fn test_stall_cycles() {
+        // LDX #$03; DEX; DEX
+        let mut cpu = cpu_with_program(&[0xA2, 0x03, 0xCA, 0xCA]);
+        let start = cpu.total_cycles();
+
+        assert_eq!(cpu.step_n(1), 2);
+        // OAM DMA on an odd cycle
+        cpu.add_stall_cycles(513);
+        assert_eq!(cpu.step_n(1), 513 + 2);
+        assert_eq!(cpu.core.x, 0x02);
+        assert_eq!(cpu.step_n(1), 2);
+        assert_eq!(cpu.core.x, 0x01);
+
+        assert_eq!(cpu.total_cycles() - start, 2 + 513 + 2 + 2);
+        assert_eq!(cpu.instruction_count(), 3);
+    }
+
+    #[test]
+    fn test_ora() {
+        // LDA #$0F; ORA $10; LDA #$00; ORA #$00; LDX #$01; ORA $20FF,X
+        let mut cpu = cpu_with_program(&[
+            0xA9, 0x0F, 0x05, 0x10, 0xA9, 0x00, 0x09, 0x00, 0xA2, 0x01, 0x1D, 0xFF, 0x20,
+        ]);
+        cpu.bus_mut().write(0x0010, 0xF0);
+        cpu.bus_mut().write(0x2100, 0x81);
+
+        cpu.step_n(2);
+        assert_eq!(cpu.core.a, 0xFF);
+        assert!(!cpu.core.get_flag(Flags::Z));
+        assert!(cpu.core.get_flag(Flags::N));
+
+        cpu.step_n(2);
+        assert_eq!(cpu.core.a, 0x00);
+        assert!(cpu.core.get_flag(Flags::Z));
+        assert!(!cpu.core.get_flag(Flags::N));
+
+        cpu.step_n(1);
+        // page cross
+        assert_eq!(cpu.step_n(1), 5);
+        assert_eq!(cpu.core.a, 0x81);
+        assert!(cpu.core.get_flag(Flags::N));
+    }
+
+    #[test]
+    fn test_and() {
+        // LDA #$F3; AND $10; AND #$0C; LDA #$FF; LDY #$02; AND $20FE,Y
+        let mut cpu = cpu_with_program(&[
+            0xA9, 0xF3, 0x25, 0x10, 0x29, 0x0C, 0xA9, 0xFF, 0xA0, 0x02, 0x39, 0xFE, 0x20,
+        ]);
+        cpu.bus_mut().write(0x0010, 0x9E);
+        cpu.bus_mut().write(0x2100, 0x80);
+
+        cpu.step_n(2);
+        assert_eq!(cpu.core.a, 0x92);
+        assert!(!cpu.core.get_flag(Flags::Z));
+        assert!(cpu.core.get_flag(Flags::N));
+
+        cpu.step_n(1);
+        assert_eq!(cpu.core.a, 0x00);
+        assert!(cpu.core.get_flag(Flags::Z));
+        assert!(!cpu.core.get_flag(Flags::N));
+
+        cpu.step_n(2);
+        // page cross
+        assert_eq!(cpu.step_n(1), 5);
+        assert_eq!(cpu.core.a, 0x80);
+        assert!(!cpu.core.get_flag(Flags::Z));
+        assert!(cpu.core.get_flag(Flags::N));
+    }
+
+    #[test]
+    fn test_eor() {
+        // LDA #$5A; EOR $10; LDA #$01; EOR #$80; EOR #$80
+        let mut cpu =
+            cpu_with_program(&[0xA9, 0x5A, 0x45, 0x10, 0xA9, 0x01, 0x49, 0x80, 0x49, 0x80]);
+        cpu.bus_mut().write(0x0010, 0x5A);
+
+        // A ^ A
+        cpu.step_n(2);
+        assert_eq!(cpu.core.a, 0x00);
+        assert!(cpu.core.get_flag(Flags::Z));
+        assert!(!cpu.core.get_flag(Flags::N));
+
+        // flipping bit 7 toggles N
+        cpu.step_n(2);
+        assert_eq!(cpu.core.a, 0x81);
+        assert!(!cpu.core.get_flag(Flags::Z));
+        assert!(cpu.core.get_flag(Flags::N));
+        cpu.step_n(1);
+        assert_eq!(cpu.core.a, 0x01);
+        assert!(!cpu.core.get_flag(Flags::N));
+    }
+
+    #[test]
+    fn test_bit() {
+        // (A, operand, expected N, V, Z)
+        let cases = [
+            (0xFF, 0xC0, true, true, false),
+            (0x3F, 0xC0, true, true, true),
+            (0xFF, 0x40, false, true, false),
+            (0x80, 0x40, false, true, true),
+            (0xFF, 0x00, false, false, true),
+            (0x00, 0x00, false, false, true),
+            (0x01, 0x81, true, false, false),
+        ];
+        for (a, operand, n, v, z) in cases {
+            // LDA #a; BIT $10; BIT $0200
+            let mut cpu = cpu_with_program(&[0xA9, a, 0x24, 0x10, 0x2C, 0x00, 0x02]);
+            cpu.bus_mut().write(0x0010, operand);
+            cpu.bus_mut().write(0x0200, operand);
+            for cycles in [2, 3, 4] {
+                assert_eq!(cpu.step_n(1), cycles);
+            }
+
+            let flags = (
+                cpu.core.get_flag(Flags::N),
+                cpu.core.get_flag(Flags::V),
+                cpu.core.get_flag(Flags::Z),
+            );
+            assert_eq!(flags, (n, v, z), "A=${:>02X} operand=${:>02X}", a, operand);
+            // A is left untouched
+            assert_eq!(cpu.core.a, a);
+        }
+    }
+
+    #[test]
+    fn test_sbc() {
+        // (A, operand, carry in, result, C, Z, V, N)
+        let cases = [
+            (0x05, 0x03, true, 0x02, true, false, false, false),
+            (0x03, 0x05, true, 0xFE, false, false, false, true),
+            (0x05, 0x05, true, 0x00, true, true, false, false),
+            // borrow in
+            (0x05, 0x03, false, 0x01, true, false, false, false),
+            // -128 - 1 overflows to +127
+            (0x80, 0x01, true, 0x7F, true, false, true, false),
+            // 127 - -1 overflows to -128
+            (0x7F, 0xFF, true, 0x80, false, false, true, true),
+        ];
+        for (a, operand, carry, result, c, z, v, n) in cases {
+            // LDA #a; SBC $10
+            let mut cpu = cpu_with_program(&[0xA9, a, 0xE5, 0x10]);
+            cpu.bus_mut().write(0x0010, operand);
+            cpu.core.set_flag(Flags::C, carry);
+            cpu.step_n(2);
+
+            let context = format!("${:>02X} - ${:>02X} (C={})", a, operand, carry);
+            assert_eq!(cpu.core.a, result, "{}", context);
+            let flags = (
+                cpu.core.get_flag(Flags::C),
+                cpu.core.get_flag(Flags::Z),
+                cpu.core.get_flag(Flags::V),
+                cpu.core.get_flag(Flags::N),
+            );
+            assert_eq!(flags, (c, z, v, n), "{}", context);
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        // (register, operand, C, Z, N)
+        let cases = [
+            (0x10, 0x20, false, false, true),
+            (0x20, 0x20, true, true, false),
+            (0x20, 0x10, true, false, false),
+            (0xFF, 0x00, true, false, true),
+        ];
+        for (register, operand, c, z, n) in cases {
+            // LDA #r; LDX #r; LDY #r, then each compare against $10
+            for compare in [0xC5, 0xE4, 0xC4] {
+                let mut cpu = cpu_with_program(&[
+                    0xA9, register, 0xA2, register, 0xA0, register, compare, 0x10,
+                ]);
+                cpu.bus_mut().write(0x0010, operand);
+                cpu.step_n(4);
+
+                let flags = (
+                    cpu.core.get_flag(Flags::C),
+                    cpu.core.get_flag(Flags::Z),
+                    cpu.core.get_flag(Flags::N),
+                );
+                let context = format!("${:>02X} ${:>02X} vs ${:>02X}", compare, register, operand);
+                assert_eq!(flags, (c, z, n), "{}", context);
+                assert_eq!(
+                    (cpu.core.a, cpu.core.x, cpu.core.y),
+                    (register, register, register),
+                    "{}",
+                    context
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_inc_dec() {
+        // INC $10; INC $10; DEC $0200; LDX #$01; DEC $01FF,X; INC $0201
+        let mut cpu = cpu_with_program(&[
+            0xE6, 0x10, 0xE6, 0x10, 0xCE, 0x00, 0x02, 0xA2, 0x01, 0xDE, 0xFF, 0x01, 0xEE, 0x01,
+            0x02,
+        ]);
+        cpu.bus_mut().write(0x0010, 0xFE);
+        cpu.bus_mut().write(0x0200, 0x01);
+        cpu.bus_mut().write(0x0201, 0xFF);
+        let peek = |cpu: &Cpu, addr| cpu.bus().peek(addr);
+
+        cpu.step_n(1);
+        assert_eq!(peek(&cpu, 0x0010), 0xFF);
+        assert!(cpu.core.get_flag(Flags::N));
+        // wraps to 0
+        cpu.step_n(1);
+        assert_eq!(peek(&cpu, 0x0010), 0x00);
+        assert!(cpu.core.get_flag(Flags::Z));
+        assert!(!cpu.core.get_flag(Flags::N));
+        assert_eq!(cpu.last_writes(), &[(0x0010, 0xFF, 0x00)]);
+
+        cpu.step_n(1);
+        assert_eq!(peek(&cpu, 0x0200), 0x00);
+        assert!(cpu.core.get_flag(Flags::Z));
+
+        // no page cross penalty for read-modify-write
+        cpu.step_n(1);
+        assert_eq!(cpu.step_n(1), 7);
+        assert_eq!(peek(&cpu, 0x0200), 0xFF);
+        assert!(cpu.core.get_flag(Flags::N));
+
+        cpu.step_n(1);
+        assert_eq!(peek(&cpu, 0x0201), 0x00);
+        assert!(cpu.core.get_flag(Flags::Z));
+    }
+
+    #[test]
+    fn test_inx_iny() {
+        // LDX #$FF; INX; LDY #$7F; INY
+        let mut cpu = cpu_with_program(&[0xA2, 0xFF, 0xE8, 0xA0, 0x7F, 0xC8]);
+        cpu.step_n(2);
+        assert_eq!(cpu.core.x, 0x00);
+        assert!(cpu.core.get_flag(Flags::Z));
+        assert!(!cpu.core.get_flag(Flags::N));
+
+        cpu.step_n(2);
+        assert_eq!(cpu.core.y, 0x80);
+        assert!(!cpu.core.get_flag(Flags::Z));
+        assert!(cpu.core.get_flag(Flags::N));
+    }
+
+    #[test]
+    fn test_asl() {
+        // LDA #$81; ASL A; ASL A; ASL $10; ASL $10
+        let mut cpu = cpu_with_program(&[0xA9, 0x81, 0x0A, 0x0A, 0x06, 0x10, 0x06, 0x10]);
+        cpu.bus_mut().write(0x0010, 0x80);
+        let peek = |cpu: &Cpu, addr| cpu.bus().peek(addr);
+
+        cpu.step_n(2);
+        assert_eq!(cpu.core.a, 0x02);
+        assert!(cpu.core.get_flag(Flags::C));
+        assert!(!cpu.core.get_flag(Flags::Z));
+        cpu.step_n(1);
+        assert_eq!(cpu.core.a, 0x04);
+        assert!(!cpu.core.get_flag(Flags::C));
+
+        // memory form leaves A alone
+        assert_eq!(cpu.step_n(1), 5);
+        assert_eq!(peek(&cpu, 0x0010), 0x00);
+        assert_eq!(cpu.core.a, 0x04);
+        assert!(cpu.core.get_flag(Flags::C));
+        assert!(cpu.core.get_flag(Flags::Z));
+        cpu.step_n(1);
+        assert_eq!(peek(&cpu, 0x0010), 0x00);
+        assert!(!cpu.core.get_flag(Flags::C));
+
+        cpu.bus_mut().write(0x0010, 0x40);
+        cpu.core.pc = 0x8006;
+        cpu.step_n(1);
+        assert_eq!(peek(&cpu, 0x0010), 0x80);
+        assert!(cpu.core.get_flag(Flags::N));
+    }
+
+    #[test]
+    fn test_lsr() {
+        // LDA #$FF; LSR A; LSR $10; LSR $10
+        let mut cpu = cpu_with_program(&[0xA9, 0xFF, 0x4A, 0x46, 0x10, 0x46, 0x10]);
+        cpu.bus_mut().write(0x0010, 0x02);
+        let peek = |cpu: &Cpu, addr| cpu.bus().peek(addr);
+
+        // odd number: carry out, and N cleared even though A was negative
+        cpu.step_n(1);
+        assert!(cpu.core.get_flag(Flags::N));
+        cpu.step_n(1);
+        assert_eq!(cpu.core.a, 0x7F);
+        assert!(cpu.core.get_flag(Flags::C));
+        assert!(!cpu.core.get_flag(Flags::N));
+
+        assert_eq!(cpu.step_n(1), 5);
+        assert_eq!(peek(&cpu, 0x0010), 0x01);
+        assert_eq!(cpu.core.a, 0x7F);
+        assert!(!cpu.core.get_flag(Flags::C));
+        assert!(!cpu.core.get_flag(Flags::Z));
+
+        cpu.step_n(1);
+        assert_eq!(peek(&cpu, 0x0010), 0x00);
+        assert!(cpu.core.get_flag(Flags::C));
+        assert!(cpu.core.get_flag(Flags::Z));
+        assert!(!cpu.core.get_flag(Flags::N));
+    }
+
+    #[test]
+    fn test_rol() {
+        // LDA #$80; ROL A; ROL A; ROL $10
+        let mut cpu = cpu_with_program(&[0xA9, 0x80, 0x2A, 0x2A, 0x26, 0x10]);
+        cpu.bus_mut().write(0x0010, 0x80);
+
+        // carry clear: bit 7 goes to carry, nothing comes in
+        cpu.step_n(2);
+        assert_eq!(cpu.core.a, 0x00);
+        assert!(cpu.core.get_flag(Flags::C));
+        assert!(cpu.core.get_flag(Flags::Z));
+
+        // and makes it back around into bit 0
+        cpu.step_n(1);
+        assert_eq!(cpu.core.a, 0x01);
+        assert!(!cpu.core.get_flag(Flags::C));
+        assert!(!cpu.core.get_flag(Flags::Z));
+
+        // carry set, in memory
+        cpu.core.set_flag(Flags::C, true);
+        assert_eq!(cpu.step_n(1), 5);
+        assert_eq!(cpu.bus().peek(0x0010), 0x01);
+        assert!(cpu.core.get_flag(Flags::C));
+        assert_eq!(cpu.core.a, 0x01);
+    }
+
+    #[test]
+    fn test_ror() {
+        // LDA #$01; ROR A; ROR A; ROR $10
+        let mut cpu = cpu_with_program(&[0xA9, 0x01, 0x6A, 0x6A, 0x66, 0x10]);
+        cpu.bus_mut().write(0x0010, 0x01);
+
+        // carry in to bit 7, bit 0 out to carry
+        cpu.step_n(1);
+        cpu.core.set_flag(Flags::C, true);
+        cpu.step_n(1);
+        assert_eq!(cpu.core.a, 0x80);
+        assert!(cpu.core.get_flag(Flags::C));
+        assert!(cpu.core.get_flag(Flags::N));
+
+        cpu.step_n(1);
+        assert_eq!(cpu.core.a, 0xC0);
+        assert!(!cpu.core.get_flag(Flags::C));
+
+        // carry clear, in memory
+        assert_eq!(cpu.step_n(1), 5);
+        assert_eq!(cpu.bus().peek(0x0010), 0x00);
+        assert!(cpu.core.get_flag(Flags::C));
+        assert!(cpu.core.get_flag(Flags::Z));
+        assert!(!cpu.core.get_flag(Flags::N));
+        assert_eq!(cpu.core.a, 0xC0);
+    }
+
+    #[test]
+    fn test_jmp() {
+        // JMP $8010 / at $8010: JMP ($00FF) / at $1234: JMP ($02FF)
+        let mut cpu = cpu_with_program(&[0x4C, 0x10, 0x80]);
+        {
+            let bus = cpu.bus_mut();
+            for (addr, byte) in [(0x8010, 0x6C), (0x8011, 0xFF), (0x8012, 0x00)] {
+                bus.write(addr, byte);
+            }
+            for (addr, byte) in [(0x1234, 0x6C), (0x1235, 0xFF), (0x1236, 0x02)] {
+                bus.write(addr, byte);
+            }
+            // the high byte of the pointer is read from the start of the same page
+            bus.write(0x00FF, 0x34);
+            bus.write(0x0000, 0x12);
+            bus.write(0x0100, 0x56);
+            bus.write(0x02FF, 0x00);
+            bus.write(0x0200, 0x90);
+            bus.write(0x0300, 0xA0);
+        }
+
+        assert_eq!(cpu.step_n(1), 3);
+        assert_eq!(cpu.core.pc, 0x8010);
+        assert_eq!(cpu.step_n(1), 5);
+        assert_eq!(cpu.core.pc, 0x1234);
+        cpu.step_n(1);
+        assert_eq!(cpu.core.pc, 0x9000);
+    }
+
+    #[test]
+    fn test_jsr() {
+        // NOP; JSR $9000
+        let mut cpu = cpu_with_program(&[0xEA, 0x20, 0x00, 0x90]);
+        cpu.step_n(1);
+        assert_eq!(cpu.core.sp, 0xFD);
+
+        assert_eq!(cpu.step_n(1), 6);
+        assert_eq!(cpu.core.pc, 0x9000);
+        assert_eq!(cpu.core.sp, 0xFB);
+        // high byte first, the address pushed is the last byte of the JSR
+        let bus = cpu.bus();
+        let pushed = ((bus.peek(0x01FD) as u16) << 8) | bus.peek(0x01FC) as u16;
+        assert_eq!(pushed, 0x8004 - 1);
+    }
+
+    #[test]
+    fn test_jsr_rts() {
+        // JSR $8010; LDX #$01 / at $8010: LDA #$42; RTS
+        let mut cpu = cpu_with_program(&[0x20, 0x10, 0x80, 0xA2, 0x01]);
+        for (addr, byte) in [(0x8010, 0xA9), (0x8011, 0x42), (0x8012, 0x60)] {
+            cpu.bus_mut().write(addr, byte);
+        }
+
+        cpu.step_n(2);
+        assert_eq!(cpu.core.a, 0x42);
+        assert_eq!(cpu.step_n(1), 6);
+        assert_eq!(cpu.core.pc, 0x8003);
+        assert_eq!(cpu.core.sp, 0xFD);
+        cpu.step_n(1);
+        assert_eq!(cpu.core.x, 0x01);
+    }
+
+    #[test]
+    fn test_brk() {
+        // NOP; BRK; (padding)
+        let mut cpu = cpu_with_program(&[0xEA, 0x00, 0xFF]);
+        cpu.bus_mut().write(0xFFFE, 0x34);
+        cpu.bus_mut().write(0xFFFF, 0x12);
+        cpu.core.set_status_byte(0x00);
+        cpu.core.set_flag(Flags::C, true);
+
+        cpu.step_n(1);
+        assert_eq!(cpu.step_n(1), 7);
+        assert_eq!(cpu.core.pc, 0x1234);
+        assert_eq!(cpu.core.sp, 0xFA);
+        assert!(cpu.core.get_flag(Flags::I));
+        assert!(!cpu.core.get_flag(Flags::B));
+
+        let bus = cpu.bus();
+        // return address skips the padding byte
+        assert_eq!(bus.peek(0x01FD), 0x80);
+        assert_eq!(bus.peek(0x01FC), 0x03);
+        // status with B and U set, as it was before the interrupt
+        assert_eq!(bus.peek(0x01FB), (Flags::B | Flags::U | Flags::C).bits());
+    }
+
+    #[test]
+    fn test_rti() {
+        // RTI, with an interrupt frame pushed by hand
+        let mut cpu = cpu_with_program(&[0x40]);
+        cpu.core.push(0x90);
+        cpu.core.push(0x12);
+        cpu.core.push((Flags::N | Flags::C | Flags::B).bits());
+        assert_eq!(cpu.core.sp, 0xFA);
+
+        assert_eq!(cpu.step_n(1), 6);
+        assert_eq!(cpu.core.pc, 0x9012);
+        assert_eq!(cpu.core.sp, 0xFD);
+        // I was set by reset and is restored from the stack, B is dropped
+        assert_eq!(
+            cpu.core.status_byte(),
+            (Flags::N | Flags::C | Flags::U).bits()
+        );
+    }
+
+    #[test]
+    fn test_brk_rti() {
+        // LDX #$00; BRK; (padding); INX / handler at $9000: LDX #$10; RTI
+        let mut cpu = cpu_with_program(&[0xA2, 0x00, 0x00, 0xFF, 0xE8]);
+        for (addr, byte) in [(0xFFFE, 0x00), (0xFFFF, 0x90)] {
+            cpu.bus_mut().write(addr, byte);
+        }
+        for (addr, byte) in [(0x9000, 0xA2), (0x9001, 0x10), (0x9002, 0x40)] {
+            cpu.bus_mut().write(addr, byte);
+        }
+        cpu.step_n(1);
+        let status = cpu.core.status_byte();
+
+        cpu.step_n(3);
+        assert_eq!(cpu.core.pc, 0x8004);
+        assert_eq!(cpu.core.sp, 0xFD);
+        assert_eq!(cpu.core.status_byte(), status);
+        cpu.step_n(1);
+        assert_eq!(cpu.core.x, 0x11);
+    }
+
+    #[test]
+    fn test_flag_instructions() {
+        let cases = [
+            (0x18, Flags::C, false),
+            (0x38, Flags::C, true),
+            (0x58, Flags::I, false),
+            (0x78, Flags::I, true),
+            (0xB8, Flags::V, false),
+            (0xD8, Flags::D, false),
+            (0xF8, Flags::D, true),
+        ];
+        for (opcode, flag, value) in cases {
+            // from all flags set and from all cleared, only `flag` may change
+            for start in [0xFF, 0x00] {
+                let mut cpu = cpu_with_program(&[opcode]);
+                cpu.core.set_status_byte(start);
+                let before = cpu.core.status;
+
+                assert_eq!(cpu.step_n(1), 2);
+                let mut expected = before;
+                expected.set(flag, value);
+                assert_eq!(
+                    cpu.core.status, expected,
+                    "${:>02X} from ${:>02X}",
+                    opcode, start
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_pha_pla() {
+        // LDA #$80; PHA; LDA #$01; PLA
+        let mut cpu = cpu_with_program(&[0xA9, 0x80, 0x48, 0xA9, 0x01, 0x68]);
+        cpu.step_n(1);
+        assert_eq!(cpu.step_n(1), 3);
+        assert_eq!(cpu.core.sp, 0xFC);
+        assert_eq!(cpu.bus().peek(0x01FD), 0x80);
+
+        cpu.step_n(1);
+        assert!(!cpu.core.get_flag(Flags::N));
+        assert_eq!(cpu.step_n(1), 4);
+        assert_eq!(cpu.core.a, 0x80);
+        assert_eq!(cpu.core.sp, 0xFD);
+        assert!(cpu.core.get_flag(Flags::N));
+        assert!(!cpu.core.get_flag(Flags::Z));
+    }
+
+    #[test]
+    fn test_php_plp() {
+        // PHP; PLP; PLP
+        let mut cpu = cpu_with_program(&[0x08, 0x28, 0x28]);
+        cpu.core.set_status_byte((Flags::C | Flags::N).bits());
+
+        cpu.step_n(1);
+        // B and U set in the pushed copy only
+        assert_eq!(
+            cpu.bus().peek(0x01FD),
+            (Flags::C | Flags::N | Flags::B | Flags::U).bits()
+        );
+        assert_eq!(
+            cpu.core.status_byte(),
+            (Flags::C | Flags::N | Flags::U).bits()
+        );
+
+        cpu.step_n(1);
+        assert_eq!(
+            cpu.core.status_byte(),
+            (Flags::C | Flags::N | Flags::U).bits()
+        );
+        assert_eq!(cpu.core.sp, 0xFD);
+
+        // pulling 0 still leaves U set, pulling B doesn't set it
+        cpu.core.push(0x00);
+        cpu.step_n(1);
+        assert_eq!(cpu.core.status_byte(), Flags::U.bits());
+        cpu.core.push(0xFF);
+        cpu.core.pc = 0x8002;
+        cpu.step_n(1);
+        assert_eq!(cpu.core.status_byte(), (Flags::all() - Flags::B).bits());
+    }
+
+    #[test]
+    fn test_transfers() {
+        // LDA #$80; TAX; TAY; LDA #$00; TXA; LDY #$00; TYA
+        let mut cpu =
+            cpu_with_program(&[0xA9, 0x80, 0xAA, 0xA8, 0xA9, 0x00, 0x8A, 0xA0, 0x00, 0x98]);
+        cpu.step_n(3);
+        assert_eq!((cpu.core.x, cpu.core.y), (0x80, 0x80));
+        assert!(cpu.core.get_flag(Flags::N));
+
+        cpu.step_n(2);
+        assert_eq!(cpu.core.a, 0x80);
+        assert!(cpu.core.get_flag(Flags::N));
+        assert!(!cpu.core.get_flag(Flags::Z));
+
+        cpu.step_n(2);
+        assert_eq!(cpu.core.a, 0x00);
+        assert!(cpu.core.get_flag(Flags::Z));
+        assert!(!cpu.core.get_flag(Flags::N));
+    }
+
+    #[test]
+    fn test_tsx_txs() {
+        // LDX #$00; TXS; LDX #$01; TSX
+        let mut cpu = cpu_with_program(&[0xA2, 0x00, 0x9A, 0xA2, 0x01, 0xBA]);
+
+        // TXS leaves Z as LDX set it
+        cpu.step_n(1);
+        cpu.core.set_flag(Flags::Z, false);
+        cpu.step_n(1);
+        assert_eq!(cpu.core.sp, 0x00);
+        assert!(!cpu.core.get_flag(Flags::Z));
+
+        cpu.step_n(2);
+        assert_eq!(cpu.core.x, 0x00);
+        assert!(cpu.core.get_flag(Flags::Z));
+
+        cpu.core.sp = 0xFD;
+        cpu.core.pc = 0x8005;
+        cpu.step_n(1);
+        assert_eq!(cpu.core.x, 0xFD);
+        assert!(cpu.core.get_flag(Flags::N));
+    }
+
+    #[test]
+    fn test_nmi() {
+        // LDX #$01 / handler at $9000: INX; RTI
+        let mut cpu = cpu_with_program(&[0xA2, 0x01]);
+        for (addr, byte) in [
+            (0xFFFA, 0x00),
+            (0xFFFB, 0x90),
+            (0x9000, 0xE8),
+            (0x9001, 0x40),
+        ] {
+            cpu.bus_mut().write(addr, byte);
+        }
+        cpu.step_n(1);
+        // NMI ignores the I flag
+        cpu.core.set_status_byte((Flags::I | Flags::C).bits());
+
+        cpu.nmi();
+        assert_eq!(cpu.core.pc, 0x9000);
+        assert_eq!(cpu.core.sp, 0xFA);
+        assert!(cpu.core.get_flag(Flags::I));
+        {
+            let bus = cpu.bus();
+            assert_eq!((bus.peek(0x01FD), bus.peek(0x01FC)), (0x80, 0x02));
+            assert_eq!(bus.peek(0x01FB), (Flags::I | Flags::C | Flags::U).bits());
+        }
+
+        // 8 cycles for the interrupt sequence, then the handler
+        assert_eq!(cpu.step_n(1), 8);
+        assert_eq!(cpu.instruction_count(), 1);
+        cpu.step_n(2);
+        assert_eq!(cpu.core.x, 0x02);
+        assert_eq!(cpu.core.pc, 0x8002);
+        assert_eq!(cpu.core.sp, 0xFD);
+    }
+
+    #[test]
+    fn test_irq() {
+        // LDX #$01 / handler at $9000: INX; RTI
+        let mut cpu = cpu_with_program(&[0xA2, 0x01]);
+        for (addr, byte) in [
+            (0xFFFE, 0x00),
+            (0xFFFF, 0x90),
+            (0x9000, 0xE8),
+            (0x9001, 0x40),
+        ] {
+            cpu.bus_mut().write(addr, byte);
+        }
+        cpu.step_n(1);
+
+        // masked: I is set after reset
+        assert!(cpu.core.get_flag(Flags::I));
+        cpu.irq();
+        assert_eq!(cpu.core.pc, 0x8002);
+        assert_eq!(cpu.core.sp, 0xFD);
+        assert!(cpu.complete());
+
+        cpu.core.set_flag(Flags::I, false);
+        cpu.irq();
+        assert_eq!(cpu.core.pc, 0x9000);
+        assert_eq!(cpu.core.sp, 0xFA);
+        assert!(cpu.core.get_flag(Flags::I));
+        // B clear in the pushed status
+        assert_eq!(cpu.bus().peek(0x01FB), Flags::U.bits());
+
+        assert_eq!(cpu.step_n(1), 7);
+        cpu.step_n(2);
+        assert_eq!(cpu.core.x, 0x02);
+        assert_eq!(cpu.core.pc, 0x8002);
+        assert!(!cpu.core.get_flag(Flags::I));
+    }
+
+    // CLI; NOP; NOP, with both handlers at $9000: INX; RTI
+    fn cpu_with_handler() -> Cpu {
+        let mut cpu = cpu_with_program(&[0x58, 0xEA, 0xEA]);
+        for (addr, byte) in [
+            (0xFFFA, 0x00),
+            (0xFFFB, 0x90),
+            (0xFFFE, 0x00),
+            (0xFFFF, 0x90),
+            (0x9000, 0xE8),
+            (0x9001, 0x40),
+        ] {
+            cpu.bus_mut().write(addr, byte);
+        }
+        cpu
+    }
+
+    #[test]
+    fn test_irq_line() {
+        let mut cpu = cpu_with_handler();
+        // masked until CLI ran
+        cpu.set_irq_line(true);
+        cpu.step();
+        assert_eq!(cpu.core.pc, 0x8001);
+
+        assert_eq!(cpu.step(), 7);
+        assert_eq!(cpu.core.pc, 0x9000);
+        cpu.step_n(2);
+        assert_eq!(cpu.core.pc, 0x8001);
+        // still held: RTI clears I and the IRQ is taken again
+        assert_eq!(cpu.step(), 7);
+        assert_eq!(cpu.core.pc, 0x9000);
+        cpu.step_n(2);
+        assert_eq!(cpu.core.x, 0x02);
+
+        cpu.set_irq_line(false);
+        cpu.step();
+        assert_eq!(cpu.core.pc, 0x8002);
+        assert_eq!(cpu.core.x, 0x02);
+    }
+
+    #[test]
+    fn test_nmi_line() {
+        let mut cpu = cpu_with_handler();
+        cpu.step();
+        cpu.set_nmi_line(true);
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(cpu.core.pc, 0x9000);
+        cpu.step_n(2);
+        assert_eq!(cpu.core.pc, 0x8001);
+        // held high: no new edge, no new NMI
+        cpu.step();
+        assert_eq!(cpu.core.pc, 0x8002);
+        assert_eq!(cpu.core.x, 0x01);
+
+        // a new edge, even while I is set
+        cpu.core.set_flag(Flags::I, true);
+        cpu.set_nmi_line(false);
+        cpu.set_nmi_line(true);
+        cpu.step();
+        assert_eq!(cpu.core.pc, 0x9000);
+    }
+
+    #[test]
+    fn test_interrupt_log() {
+        let mut cpu = cpu_with_handler();
+        cpu.set_interrupt_logging(true);
+        cpu.step();
+        cpu.set_nmi_line(true);
+        cpu.step_n(3);
+        cpu.set_irq_line(true);
+        cpu.step();
+
+        let cycle = RESET_CYCLES + 2;
+        assert_eq!(
+            cpu.take_interrupt_log(),
+            [
+                InterruptEvent {
+                    kind: InterruptKind::Nmi,
+                    pc: 0x8001,
+                    vector: 0xFFFA,
+                    cycle,
+                },
+                // NMI, INX, RTI
+                InterruptEvent {
+                    kind: InterruptKind::Irq,
+                    pc: 0x8001,
+                    vector: 0xFFFE,
+                    cycle: cycle + 8 + 2 + 6,
+                },
+            ]
+        );
+        assert!(cpu.take_interrupt_log().is_empty());
+
+        // off by default
+        let mut cpu = cpu_with_handler();
+        cpu.set_nmi_line(true);
+        cpu.step();
+        assert!(cpu.take_interrupt_log().is_empty());
+    }
+
+    #[test]
+    fn test_stack_wraps() {
+        let mut cpu = cpu_with_program(&[]);
+        let peek = |cpu: &Cpu, addr| cpu.bus().peek(addr);
+
+        cpu.core.sp = 0x00;
+        cpu.core.push(0x42);
+        assert_eq!(cpu.core.sp, 0xFF);
+        assert_eq!(peek(&cpu, 0x0100), 0x42);
+        assert_eq!(cpu.core.pull(), 0x42);
+        assert_eq!(cpu.core.sp, 0x00);
+
+        cpu.core.sp = 0xFF;
+        cpu.core.push(0x24);
+        assert_eq!(peek(&cpu, 0x01FF), 0x24);
+        assert_eq!(cpu.core.sp, 0xFE);
+        cpu.core.sp = 0xFF;
+        assert_eq!(cpu.core.pull(), peek(&cpu, 0x0100));
+        assert_eq!(cpu.core.sp, 0x00);
+
+        // a word across the wrap: high byte at $0100, low byte at $01FF
+        cpu.core.sp = 0x00;
+        cpu.core.push_u16(0x1234);
+        assert_eq!(cpu.core.sp, 0xFE);
+        assert_eq!((peek(&cpu, 0x0100), peek(&cpu, 0x01FF)), (0x12, 0x34));
+        assert_eq!(cpu.core.pull_u16(), 0x1234);
+        assert_eq!(cpu.core.sp, 0x00);
+    }
+
+    #[test]
+    fn test_addressing_mode() {
+        let cpu = Cpu::new(Bus::new());
+        assert_eq!(cpu.addressing_mode(0xA9), Some(Kind::IMM));
+        assert_eq!(cpu.addressing_mode(0xB6), Some(Kind::ZPY));
+        assert_eq!(cpu.addressing_mode(0x91), Some(Kind::IZY));
+        assert_eq!(cpu.addressing_mode(0xD0), Some(Kind::REL));
+        assert_eq!(cpu.addressing_mode(0xEA), Some(Kind::IMP));
+        assert_eq!(cpu.addressing_mode(0x02), None);
+    }
+
+    // Entry of the reference table at the end of this file
+    struct Reference {
+        name: String,
+        official: bool,
+        mode: Option<addr_modes::Kind>,
+        cycles: Option<usize>,
+    }
+
+    // Lines look like `04 *NOP zp 3`, `18 CLC 2` (implied mode), `0A ASL 2` (accumulator)
+    // or `02 *KIL`
+    fn reference_table() -> HashMap<u8, Reference> {
+        let source = include_str!("mod.rs");
+        let table = &source[source.rfind("// Reference").unwrap()..];
+        table
+            .lines()
+            .filter_map(|line| {
+                let mut words = line.split_whitespace();
+                let byte = u8::from_str_radix(words.next()?, 16).ok()?;
+                let name = words.next()?;
+                let official = !name.starts_with('*');
+                let name = name.trim_start_matches('*').to_string();
+                let parse_cycles = |word: Option<&str>| word?.trim_end_matches('*').parse().ok();
+
+                let word = words.next();
+                let (mode, cycles) = match word {
+                    Some("imm") => (Some(addr_modes::Kind::IMM), parse_cycles(words.next())),
+                    Some("zp") => (Some(addr_modes::Kind::ZP0), parse_cycles(words.next())),
+                    Some("zpx") => (Some(addr_modes::Kind::ZPX), parse_cycles(words.next())),
+                    Some("zpy") => (Some(addr_modes::Kind::ZPY), parse_cycles(words.next())),
+                    Some("rel") => (Some(addr_modes::Kind::REL), parse_cycles(words.next())),
+                    Some("abs") => (Some(addr_modes::Kind::ABS), parse_cycles(words.next())),
+                    Some("abx") => (Some(addr_modes::Kind::ABX), parse_cycles(words.next())),
+                    Some("aby") => (Some(addr_modes::Kind::ABY), parse_cycles(words.next())),
+                    Some("ind") => (Some(addr_modes::Kind::IND), parse_cycles(words.next())),
+                    Some("izx") => (Some(addr_modes::Kind::IZX), parse_cycles(words.next())),
+                    Some("izy") => (Some(addr_modes::Kind::IZY), parse_cycles(words.next())),
+                    _ => match parse_cycles(word) {
+                        // no operand: the shifts and rotates work on the accumulator
+                        Some(cycles) => match name.as_str() {
+                            "ASL" | "LSR" | "ROL" | "ROR" => {
+                                (Some(addr_modes::Kind::ACC), Some(cycles))
+                            }
+                            _ => (Some(addr_modes::Kind::IMP), Some(cycles)),
+                        },
+                        None => (None, None),
+                    },
+                };
+                Some((
+                    byte,
+                    Reference {
+                        name,
+                        official,
+                        mode,
+                        cycles,
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_opcode_coverage() {
+        let cpu = Cpu::new(Bus::new());
+        let reference = reference_table();
+
+        // run with --nocapture to get the full table
+        let mut registered = 0;
+        for byte in 0..=0xFFu8 {
+            let expected = reference.get(&byte).map_or("", |entry| entry.name.as_str());
+            match cpu.opcodes.get(byte) {
+                None => println!("{:>02X}  ---  {}", byte, expected),
+                Some(Opcode {
+                    name,
+                    addr_mode,
+                    cycles,
+                    ..
+                }) => {
+                    registered += 1;
+                    println!("{:>02X}  {}  {:?} {}", byte, name, addr_mode.kind(), cycles);
+                    if !expected.is_empty() {
+                        assert_eq!(name, expected, "mnemonic mismatch for ${:>02X}", byte);
+                    }
+                }
+            }
+        }
+        assert_eq!(registered, 178);
+    }
+
+    #[test]
+    fn test_opcode_table() {
+        let cpu = Cpu::new(Bus::new());
+
+        // every slot holds an opcode, the unknown ones the XXX placeholder
+        assert_eq!(cpu.opcodes.0.len(), 256);
+        for byte in 0..=0xFFu8 {
+            let opcode = &cpu.opcodes[byte];
+            assert!(!opcode.name.is_empty(), "empty slot ${:>02X}", byte);
+            assert_eq!(cpu.opcodes.get(byte).is_some(), opcode.known);
+            // every instruction takes time, the 0 cycle case can't be clocked
+            assert!(opcode.cycles >= 2, "${:>02X} runs in no time", byte);
+        }
+        let unknown = (0..=0xFFu8).filter(|&byte| cpu.opcodes.get(byte).is_none());
+        assert_eq!(unknown.count(), 256 - 178);
+
+        let Opcode {
+            name,
+            addr_mode,
+            cycles,
+            ..
+        } = &cpu.opcodes[0xBD];
+        assert_eq!(
+            (name.as_str(), addr_mode.kind(), *cycles),
+            ("LDA", Kind::ABX, 4)
+        );
+        assert_eq!(cpu.opcodes[0x02].name, "XXX");
+        assert!(!cpu.opcodes[0x02].known);
+
+        // and the slot runs that operation: LDA $8003,X with X = 0
+        let mut cpu = cpu_with_program(&[0xBD, 0x03, 0x80, 0x42]);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.core.a, 0x42);
+    }
+
+    #[test]
+    fn test_opcode_is_official() {
+        assert!(opcode_is_official(0xA9)); // LDA #
+        assert!(opcode_is_official(0xEA)); // NOP
+        assert!(!opcode_is_official(0xA3)); // LAX (zp,X)
+        assert!(!opcode_is_official(0x1A)); // NOP
+        assert!(!opcode_is_official(0x02)); // KIL
+
+        // implemented opcodes are dropped from the reference table, the rest must agree
+        for (byte, entry) in reference_table() {
+            assert_eq!(opcode_is_official(byte), entry.official, "${:>02X}", byte);
+        }
+        assert_eq!((0..=0xFFu8).filter(|&b| opcode_is_official(b)).count(), 151);
+    }
+
+    // The disassembler decodes operands from the addressing mode of the opcode table,
+    // so a wrong mode there means execution and disassembly disagree on the length
+    #[test]
+    fn test_opcode_table_matches_reference() {
+        let cpu = Cpu::new(Bus::new());
+        for (byte, entry) in reference_table() {
+            let Some(opcode) = cpu.opcodes.get(byte) else {
+                continue;
+            };
+            if let Some(mode) = entry.mode {
+                assert_eq!(
+                    opcode.addr_mode.kind(),
+                    mode,
+                    "addressing mode mismatch for ${:>02X} {}",
+                    byte,
+                    opcode.name
+                );
+            }
+            if let Some(cycles) = entry.cycles {
+                assert_eq!(
+                    opcode.cycles, cycles,
+                    "cycles mismatch for ${:>02X} {}",
+                    byte, opcode.name
+                );
+            }
+        }
     }
 }
 
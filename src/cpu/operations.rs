@@ -1,11 +1,19 @@
 #![allow(clippy::upper_case_acronyms)]
-use super::{CpuCore, Flags, Opcode, Operation};
-use std::collections::HashMap;
+use super::addr_modes::Kind;
+use super::{CpuCore, Flags, InterruptKind, Opcodes, Operation};
+
+// Result of a shift or rotate: back into A in accumulator mode, to memory otherwise
+fn write_back(opcodes: &Opcodes, cpu: &mut CpuCore, value: u8) {
+    match opcodes[cpu.opcode].addr_mode.kind() {
+        Kind::ACC => cpu.a = value,
+        _ => cpu.write(cpu.addr_abs, value),
+    }
+}
 
 pub struct XXX {}
 
 impl Operation for XXX {
-    fn run(&self, _: &HashMap<u8, Opcode>, _: &mut CpuCore) -> u8 {
+    fn run(&self, _: &Opcodes, _: &mut CpuCore) -> u8 {
         0
     }
 }
@@ -13,7 +21,7 @@ impl Operation for XXX {
 pub struct LDA {}
 
 impl Operation for LDA {
-    fn run(&self, opcodes: &HashMap<u8, Opcode>, cpu: &mut CpuCore) -> u8 {
+    fn run(&self, opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
         let a = cpu.fetch(opcodes);
         cpu.a = a;
         cpu.set_flag(Flags::Z, a == 0x00);
@@ -25,7 +33,7 @@ impl Operation for LDA {
 pub struct LDX {}
 
 impl Operation for LDX {
-    fn run(&self, opcodes: &HashMap<u8, Opcode>, cpu: &mut CpuCore) -> u8 {
+    fn run(&self, opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
         let x = cpu.fetch(opcodes);
         cpu.x = x;
         cpu.set_flag(Flags::Z, x == 0x00);
@@ -37,7 +45,7 @@ impl Operation for LDX {
 pub struct LDY {}
 
 impl Operation for LDY {
-    fn run(&self, opcodes: &HashMap<u8, Opcode>, cpu: &mut CpuCore) -> u8 {
+    fn run(&self, opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
         let y = cpu.fetch(opcodes);
         cpu.y = y;
         cpu.set_flag(Flags::Z, y == 0x00);
@@ -49,7 +57,7 @@ impl Operation for LDY {
 pub struct STA {}
 
 impl Operation for STA {
-    fn run(&self, _opcodes: &HashMap<u8, Opcode>, cpu: &mut CpuCore) -> u8 {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
         cpu.write(cpu.addr_abs, cpu.a);
         0
     }
@@ -58,7 +66,7 @@ impl Operation for STA {
 pub struct STX {}
 
 impl Operation for STX {
-    fn run(&self, _opcodes: &HashMap<u8, Opcode>, cpu: &mut CpuCore) -> u8 {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
         cpu.write(cpu.addr_abs, cpu.x);
         0
     }
@@ -67,7 +75,7 @@ impl Operation for STX {
 pub struct STY {}
 
 impl Operation for STY {
-    fn run(&self, _opcodes: &HashMap<u8, Opcode>, cpu: &mut CpuCore) -> u8 {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
         cpu.write(cpu.addr_abs, cpu.y);
         0
     }
@@ -76,16 +84,70 @@ impl Operation for STY {
 pub struct CLC {}
 
 impl Operation for CLC {
-    fn run(&self, _opcodes: &HashMap<u8, Opcode>, cpu: &mut CpuCore) -> u8 {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
         cpu.set_flag(Flags::C, false);
         0
     }
 }
 
+pub struct SEC {}
+
+impl Operation for SEC {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.set_flag(Flags::C, true);
+        0
+    }
+}
+
+pub struct CLI {}
+
+impl Operation for CLI {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.set_flag(Flags::I, false);
+        0
+    }
+}
+
+pub struct SEI {}
+
+impl Operation for SEI {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.set_flag(Flags::I, true);
+        0
+    }
+}
+
+pub struct CLV {}
+
+impl Operation for CLV {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.set_flag(Flags::V, false);
+        0
+    }
+}
+
+pub struct CLD {}
+
+impl Operation for CLD {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.set_flag(Flags::D, false);
+        0
+    }
+}
+
+pub struct SED {}
+
+impl Operation for SED {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.set_flag(Flags::D, true);
+        0
+    }
+}
+
 pub struct ADC {}
 
 impl Operation for ADC {
-    fn run(&self, opcodes: &HashMap<u8, Opcode>, cpu: &mut CpuCore) -> u8 {
+    fn run(&self, opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
         let fetched = cpu.fetch(opcodes) as u16;
 
         // working in u16 to catch overflow more easily
@@ -105,10 +167,286 @@ impl Operation for ADC {
     }
 }
 
+pub struct AND {}
+
+impl Operation for AND {
+    fn run(&self, opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.a &= cpu.fetch(opcodes);
+        cpu.set_flag(Flags::Z, cpu.a == 0x00);
+        cpu.set_flag(Flags::N, (cpu.a & 0x80) != 0);
+        1
+    }
+}
+
+// N and V come straight from the operand, only Z depends on A
+pub struct BIT {}
+
+impl Operation for BIT {
+    fn run(&self, opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        let fetched = cpu.fetch(opcodes);
+        cpu.set_flag(Flags::Z, (cpu.a & fetched) == 0x00);
+        cpu.set_flag(Flags::N, (fetched & 0x80) != 0);
+        cpu.set_flag(Flags::V, (fetched & 0x40) != 0);
+        0
+    }
+}
+
+pub struct EOR {}
+
+impl Operation for EOR {
+    fn run(&self, opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.a ^= cpu.fetch(opcodes);
+        cpu.set_flag(Flags::Z, cpu.a == 0x00);
+        cpu.set_flag(Flags::N, (cpu.a & 0x80) != 0);
+        1
+    }
+}
+
+pub struct ORA {}
+
+impl Operation for ORA {
+    fn run(&self, opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.a |= cpu.fetch(opcodes);
+        cpu.set_flag(Flags::Z, cpu.a == 0x00);
+        cpu.set_flag(Flags::N, (cpu.a & 0x80) != 0);
+        1
+    }
+}
+
+pub struct SBC {}
+
+impl Operation for SBC {
+    fn run(&self, opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        // A - M - (1 - C) == A + !M + C, so this is ADC with the operand inverted
+        let value = (cpu.fetch(opcodes) as u16) ^ 0x00FF;
+
+        let a = cpu.a as u16;
+        let c = cpu.get_flag(Flags::C) as u16;
+
+        let temp = a + value + c;
+
+        cpu.set_flag(Flags::C, temp > 255);
+        cpu.set_flag(Flags::Z, (temp & 0x00FF) == 0);
+        let v = (temp ^ a) & (temp ^ value);
+        cpu.set_flag(Flags::V, (v & 0x0080) != 0);
+        cpu.set_flag(Flags::N, (temp & 0x0080) != 0);
+
+        cpu.a = (temp & 0x00FF) as u8;
+        1
+    }
+}
+
+// Shared by CMP/CPX/CPY: flags of `register - operand`, registers are left untouched
+fn compare(cpu: &mut CpuCore, register: u8, value: u8) {
+    let temp = (register as u16).wrapping_sub(value as u16);
+    cpu.set_flag(Flags::C, register >= value);
+    cpu.set_flag(Flags::Z, (temp & 0x00FF) == 0);
+    cpu.set_flag(Flags::N, (temp & 0x0080) != 0);
+}
+
+pub struct CMP {}
+
+impl Operation for CMP {
+    fn run(&self, opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        let fetched = cpu.fetch(opcodes);
+        compare(cpu, cpu.a, fetched);
+        1
+    }
+}
+
+pub struct CPX {}
+
+impl Operation for CPX {
+    fn run(&self, opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        let fetched = cpu.fetch(opcodes);
+        compare(cpu, cpu.x, fetched);
+        0
+    }
+}
+
+pub struct CPY {}
+
+impl Operation for CPY {
+    fn run(&self, opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        let fetched = cpu.fetch(opcodes);
+        compare(cpu, cpu.y, fetched);
+        0
+    }
+}
+
+pub struct INC {}
+
+impl Operation for INC {
+    fn run(&self, opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        let value = cpu.fetch(opcodes).wrapping_add(1);
+        cpu.write(cpu.addr_abs, value);
+        cpu.set_flag(Flags::Z, value == 0x00);
+        cpu.set_flag(Flags::N, (value & 0x80) != 0);
+        0
+    }
+}
+
+pub struct DEC {}
+
+impl Operation for DEC {
+    fn run(&self, opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        let value = cpu.fetch(opcodes).wrapping_sub(1);
+        cpu.write(cpu.addr_abs, value);
+        cpu.set_flag(Flags::Z, value == 0x00);
+        cpu.set_flag(Flags::N, (value & 0x80) != 0);
+        0
+    }
+}
+
+pub struct TAX {}
+
+impl Operation for TAX {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.x = cpu.a;
+        cpu.set_flag(Flags::Z, cpu.x == 0x00);
+        cpu.set_flag(Flags::N, (cpu.x & 0x80) != 0);
+        0
+    }
+}
+
+pub struct TAY {}
+
+impl Operation for TAY {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.y = cpu.a;
+        cpu.set_flag(Flags::Z, cpu.y == 0x00);
+        cpu.set_flag(Flags::N, (cpu.y & 0x80) != 0);
+        0
+    }
+}
+
+pub struct TSX {}
+
+impl Operation for TSX {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.x = cpu.sp;
+        cpu.set_flag(Flags::Z, cpu.x == 0x00);
+        cpu.set_flag(Flags::N, (cpu.x & 0x80) != 0);
+        0
+    }
+}
+
+pub struct TXA {}
+
+impl Operation for TXA {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.a = cpu.x;
+        cpu.set_flag(Flags::Z, cpu.a == 0x00);
+        cpu.set_flag(Flags::N, (cpu.a & 0x80) != 0);
+        0
+    }
+}
+
+pub struct TXS {}
+
+impl Operation for TXS {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        // the only transfer that leaves the flags alone
+        cpu.sp = cpu.x;
+        0
+    }
+}
+
+pub struct TYA {}
+
+impl Operation for TYA {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.a = cpu.y;
+        cpu.set_flag(Flags::Z, cpu.a == 0x00);
+        cpu.set_flag(Flags::N, (cpu.a & 0x80) != 0);
+        0
+    }
+}
+
+pub struct INX {}
+
+impl Operation for INX {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.x = cpu.x.wrapping_add(1);
+        cpu.set_flag(Flags::Z, cpu.x == 0x00);
+        cpu.set_flag(Flags::N, (cpu.x & 0x80) != 0);
+        0
+    }
+}
+
+pub struct INY {}
+
+impl Operation for INY {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.y = cpu.y.wrapping_add(1);
+        cpu.set_flag(Flags::Z, cpu.y == 0x00);
+        cpu.set_flag(Flags::N, (cpu.y & 0x80) != 0);
+        0
+    }
+}
+
+pub struct ASL {}
+
+impl Operation for ASL {
+    fn run(&self, opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        let temp = (cpu.fetch(opcodes) as u16) << 1;
+        cpu.set_flag(Flags::C, (temp & 0xFF00) != 0);
+        cpu.set_flag(Flags::Z, (temp & 0x00FF) == 0);
+        cpu.set_flag(Flags::N, (temp & 0x0080) != 0);
+        write_back(opcodes, cpu, (temp & 0x00FF) as u8);
+        0
+    }
+}
+
+pub struct LSR {}
+
+impl Operation for LSR {
+    fn run(&self, opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        let fetched = cpu.fetch(opcodes);
+        cpu.set_flag(Flags::C, (fetched & 0x01) != 0);
+        let value = fetched >> 1;
+        cpu.set_flag(Flags::Z, value == 0x00);
+        // bit 7 is always shifted in as 0
+        cpu.set_flag(Flags::N, false);
+        write_back(opcodes, cpu, value);
+        0
+    }
+}
+
+pub struct ROL {}
+
+impl Operation for ROL {
+    fn run(&self, opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        let carry_in = cpu.get_flag(Flags::C) as u16;
+        let temp = ((cpu.fetch(opcodes) as u16) << 1) | carry_in;
+        cpu.set_flag(Flags::C, (temp & 0xFF00) != 0);
+        cpu.set_flag(Flags::Z, (temp & 0x00FF) == 0);
+        cpu.set_flag(Flags::N, (temp & 0x0080) != 0);
+        write_back(opcodes, cpu, (temp & 0x00FF) as u8);
+        0
+    }
+}
+
+pub struct ROR {}
+
+impl Operation for ROR {
+    fn run(&self, opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        let fetched = cpu.fetch(opcodes);
+        // the old carry becomes bit 7, read it before C is overwritten by bit 0
+        let carry_in = (cpu.get_flag(Flags::C) as u8) << 7;
+        let value = carry_in | (fetched >> 1);
+        cpu.set_flag(Flags::C, (fetched & 0x01) != 0);
+        cpu.set_flag(Flags::Z, value == 0x00);
+        cpu.set_flag(Flags::N, (value & 0x80) != 0);
+        write_back(opcodes, cpu, value);
+        0
+    }
+}
+
 pub struct DEX {}
 
 impl Operation for DEX {
-    fn run(&self, _opcodes: &HashMap<u8, Opcode>, cpu: &mut CpuCore) -> u8 {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
         cpu.x = cpu.x.wrapping_sub(1);
         cpu.set_flag(Flags::Z, cpu.x == 0x00);
         cpu.set_flag(Flags::N, (cpu.x & 0x80) != 0);
@@ -119,7 +457,7 @@ impl Operation for DEX {
 pub struct DEY {}
 
 impl Operation for DEY {
-    fn run(&self, _opcodes: &HashMap<u8, Opcode>, cpu: &mut CpuCore) -> u8 {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
         cpu.y = cpu.y.wrapping_sub(1);
         cpu.set_flag(Flags::Z, cpu.y == 0x00);
         cpu.set_flag(Flags::N, (cpu.y & 0x80) != 0);
@@ -127,20 +465,166 @@ impl Operation for DEY {
     }
 }
 
-pub struct BNE {}
+pub struct PHA {}
 
-impl Operation for BNE {
-    fn run(&self, _opcodes: &HashMap<u8, Opcode>, cpu: &mut CpuCore) -> u8 {
-        if !cpu.get_flag(Flags::Z) {
-            cpu.cycles += 1;
-            cpu.addr_abs = cpu.pc.wrapping_add(cpu.addr_rel);
+impl Operation for PHA {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.push(cpu.a);
+        0
+    }
+}
 
-            if (cpu.addr_abs & 0xFF00) != (cpu.pc & 0xFF00) {
-                cpu.cycles += 1
-            }
+pub struct PLA {}
 
-            cpu.pc = cpu.addr_abs
-        }
+impl Operation for PLA {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.a = cpu.pull();
+        cpu.set_flag(Flags::Z, cpu.a == 0x00);
+        cpu.set_flag(Flags::N, (cpu.a & 0x80) != 0);
+        0
+    }
+}
+
+pub struct PHP {}
+
+impl Operation for PHP {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        let status = cpu.status_for_push(true);
+        cpu.push(status);
+        0
+    }
+}
+
+pub struct PLP {}
+
+impl Operation for PLP {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        let status = cpu.pull();
+        cpu.set_status_byte(status);
+        0
+    }
+}
+
+pub struct JMP {}
+
+impl Operation for JMP {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.pc = cpu.addr_abs;
+        0
+    }
+}
+
+pub struct JSR {}
+
+impl Operation for JSR {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        // return address minus one, RTS adds it back
+        cpu.push_u16(cpu.pc.wrapping_sub(1));
+        cpu.pc = cpu.addr_abs;
+        0
+    }
+}
+
+pub struct RTS {}
+
+impl Operation for RTS {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.pc = cpu.pull_u16().wrapping_add(1);
+        0
+    }
+}
+
+pub struct BRK {}
+
+impl Operation for BRK {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        // BRK is followed by a padding byte, skipped on return
+        cpu.pc = cpu.pc.wrapping_add(1);
+        cpu.interrupt(InterruptKind::Brk);
+        0
+    }
+}
+
+pub struct RTI {}
+
+impl Operation for RTI {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        let status = cpu.pull();
+        cpu.set_status_byte(status);
+        // unlike RTS, the exact return address was pushed
+        cpu.pc = cpu.pull_u16();
+        0
+    }
+}
+
+pub struct BPL {}
+
+impl Operation for BPL {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.branch(!cpu.get_flag(Flags::N));
+        0
+    }
+}
+
+pub struct BMI {}
+
+impl Operation for BMI {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.branch(cpu.get_flag(Flags::N));
+        0
+    }
+}
+
+pub struct BVC {}
+
+impl Operation for BVC {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.branch(!cpu.get_flag(Flags::V));
+        0
+    }
+}
+
+pub struct BVS {}
+
+impl Operation for BVS {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.branch(cpu.get_flag(Flags::V));
+        0
+    }
+}
+
+pub struct BCC {}
+
+impl Operation for BCC {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.branch(!cpu.get_flag(Flags::C));
+        0
+    }
+}
+
+pub struct BCS {}
+
+impl Operation for BCS {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.branch(cpu.get_flag(Flags::C));
+        0
+    }
+}
+
+pub struct BEQ {}
+
+impl Operation for BEQ {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.branch(cpu.get_flag(Flags::Z));
+        0
+    }
+}
+
+pub struct BNE {}
+
+impl Operation for BNE {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
+        cpu.branch(!cpu.get_flag(Flags::Z));
         0
     }
 }
@@ -148,7 +632,7 @@ impl Operation for BNE {
 pub struct NOP {}
 
 impl Operation for NOP {
-    fn run(&self, _opcodes: &HashMap<u8, Opcode>, cpu: &mut CpuCore) -> u8 {
+    fn run(&self, _opcodes: &Opcodes, cpu: &mut CpuCore) -> u8 {
         match cpu.opcode {
             0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => 1,
             _ => 0,
@@ -0,0 +1,450 @@
+#![allow(dead_code)]
+// iNES ROM files: https://www.nesdev.org/wiki/INES
+use crate::region::Region;
+use eyre::{ensure, Result, WrapErr};
+use std::ops::Range;
+use std::path::Path;
+
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_ROM_BANK_SIZE: usize = 16 * 1024;
+const CHR_ROM_BANK_SIZE: usize = 8 * 1024;
+const CHR_RAM_SIZE: usize = 8 * 1024;
+const PRG_RAM_BANK_SIZE: usize = 8 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NametableArrangement {
+    // vertical mirroring
+    Horizontal,
+    // horizontal mirroring
+    Vertical,
+}
+
+#[derive(Debug)]
+pub struct Header {
+    prg_rom_size: usize,
+    chr_rom_size: usize,
+    prg_ram_size: usize,
+    mapper_number: u8,
+    nametable_arrangement: NametableArrangement,
+    battery: bool,
+    trainer: bool,
+    four_screen: bool,
+    tv_system: Option<Region>,
+    // As read, `to_bytes` keeps what the fields don't cover (flags 8-15, padding)
+    raw: [u8; HEADER_SIZE],
+}
+
+impl Header {
+    pub fn new(bytes: &[u8]) -> Result<Self> {
+        ensure!(bytes.len() >= HEADER_SIZE, "iNES header is too short");
+        ensure!(bytes[0..4] == *b"NES\x1A", "Missing iNES magic number");
+
+        let flags6 = bytes[6];
+        let flags7 = bytes[7];
+        let flags9 = bytes[9];
+        let flags10 = bytes[10];
+        let nametable_arrangement = if flags6 & 0x01 == 0 {
+            NametableArrangement::Vertical
+        } else {
+            NametableArrangement::Horizontal
+        };
+
+        Ok(Self {
+            prg_rom_size: bytes[4] as usize * PRG_ROM_BANK_SIZE,
+            chr_rom_size: bytes[5] as usize * CHR_ROM_BANK_SIZE,
+            // 0 means 8KB for compatibility, flags 10 bit 4 tells there is none at all
+            prg_ram_size: if flags10 & 0x10 != 0 {
+                0
+            } else {
+                bytes[8].max(1) as usize * PRG_RAM_BANK_SIZE
+            },
+            mapper_number: (flags7 & 0xF0) | (flags6 >> 4),
+            nametable_arrangement,
+            battery: flags6 & 0x02 != 0,
+            trainer: flags6 & 0x04 != 0,
+            four_screen: flags6 & 0x08 != 0,
+            // most dumps leave the flag clear, so NTSC can't be told from unknown
+            tv_system: (flags9 & 0x01 != 0).then_some(Region::Pal),
+            raw: bytes[..HEADER_SIZE].try_into().expect("Header is 16 bytes"),
+        })
+    }
+
+    // The 16 header bytes back from the fields
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = self.raw;
+        bytes[4] = (self.prg_rom_size / PRG_ROM_BANK_SIZE) as u8;
+        bytes[5] = (self.chr_rom_size / CHR_ROM_BANK_SIZE) as u8;
+        bytes[6] = (self.mapper_number << 4)
+            | (self.four_screen as u8) << 3
+            | (self.trainer as u8) << 2
+            | (self.battery as u8) << 1
+            | (self.nametable_arrangement == NametableArrangement::Horizontal) as u8;
+        bytes[7] = (self.mapper_number & 0xF0) | (bytes[7] & 0x0F);
+        bytes
+    }
+
+    pub fn mapper_number(&self) -> u8 {
+        self.mapper_number
+    }
+
+    pub fn nametable_arrangement(&self) -> NametableArrangement {
+        self.nametable_arrangement
+    }
+
+    // In bytes
+    pub fn prg_rom_size(&self) -> usize {
+        self.prg_rom_size
+    }
+
+    // In bytes, 0 when the board has CHR RAM
+    pub fn chr_rom_size(&self) -> usize {
+        self.chr_rom_size
+    }
+
+    // In bytes, 0 when the board has no PRG RAM
+    pub fn prg_ram_size(&self) -> usize {
+        self.prg_ram_size
+    }
+
+    // Flags 6 bit 3: the board brings its own VRAM for four nametables, the
+    // arrangement bit is then ignored
+    pub fn four_screen(&self) -> bool {
+        self.four_screen
+    }
+
+    // Flags 9 TV system: Some(Pal) when set, None for NTSC or unspecified
+    pub fn tv_system(&self) -> Option<Region> {
+        self.tv_system
+    }
+}
+
+#[derive(Debug)]
+pub struct INes {
+    header: Header,
+    trainer: Option<[u8; TRAINER_SIZE]>,
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    // boards without CHR ROM have 8KB of CHR RAM instead
+    chr_ram: Option<Vec<u8>>,
+    // anything after CHR ROM (e.g. PlayChoice data), only kept for `write`
+    trailer: Vec<u8>,
+}
+
+impl INes {
+    pub fn new(mut bytes: Vec<u8>) -> Result<Self> {
+        let header = Header::new(&bytes)?;
+
+        let trainer_size = if header.trainer { TRAINER_SIZE } else { 0 };
+        let expected = HEADER_SIZE + trainer_size + header.prg_rom_size + header.chr_rom_size;
+        ensure!(
+            bytes.len() >= expected,
+            "File is truncated: the header declares {} bytes ({}KB PRG ROM, {}KB CHR ROM{}), found {}",
+            expected,
+            header.prg_rom_size / 1024,
+            header.chr_rom_size / 1024,
+            if header.trainer { ", trainer" } else { "" },
+            bytes.len()
+        );
+
+        let mut data = bytes.split_off(HEADER_SIZE);
+        let trainer = if header.trainer {
+            let rest = data.split_off(TRAINER_SIZE);
+            let trainer = data.try_into().expect("Trainer is 512 bytes");
+            data = rest;
+            Some(trainer)
+        } else {
+            None
+        };
+        let mut prg_rom = data;
+        let mut chr_rom = prg_rom.split_off(header.prg_rom_size);
+        let trailer = chr_rom.split_off(header.chr_rom_size);
+        let chr_ram = (header.chr_rom_size == 0).then(|| vec![0x00; CHR_RAM_SIZE]);
+
+        Ok(Self {
+            header,
+            trainer,
+            prg_rom,
+            chr_rom,
+            chr_ram,
+            trailer,
+        })
+    }
+
+    // The iNES file: header, trainer, PRG ROM, CHR ROM and trailer
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header.to_bytes().to_vec();
+        if let Some(trainer) = &self.trainer {
+            bytes.extend(trainer);
+        }
+        bytes.extend(&self.prg_rom);
+        bytes.extend(&self.chr_rom);
+        bytes.extend(&self.trailer);
+        bytes
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_bytes())
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let bytes =
+            std::fs::read(path).wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+        Self::new(bytes).wrap_err_with(|| format!("Invalid ROM {}", path.display()))
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn trainer(&self) -> Option<&[u8; TRAINER_SIZE]> {
+        self.trainer.as_ref()
+    }
+
+    pub fn prg_rom(&self) -> &[u8] {
+        &self.prg_rom
+    }
+
+    pub fn chr_rom(&self) -> &[u8] {
+        &self.chr_rom
+    }
+
+    pub fn has_chr_ram(&self) -> bool {
+        self.chr_ram.is_some()
+    }
+
+    pub fn chr_ram(&self) -> Option<&[u8]> {
+        self.chr_ram.as_deref()
+    }
+
+    pub fn chr_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.chr_ram.as_deref_mut()
+    }
+}
+
+// Where the PRG ROM and CHR ROM of two files differ, as offset ranges in each section.
+// Header differences aren't reported.
+#[derive(Debug, PartialEq)]
+pub struct RomDiff {
+    pub prg_rom: Vec<Range<usize>>,
+    pub chr_rom: Vec<Range<usize>>,
+}
+
+impl RomDiff {
+    pub fn new(a: &INes, b: &INes) -> Self {
+        Self {
+            prg_rom: diff_ranges(a.prg_rom(), b.prg_rom()),
+            chr_rom: diff_ranges(a.chr_rom(), b.chr_rom()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prg_rom.is_empty() && self.chr_rom.is_empty()
+    }
+}
+
+// Runs of differing bytes, what is past the end of the shorter side differs too
+fn diff_ranges(a: &[u8], b: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for offset in 0..a.len().max(b.len()) {
+        if a.get(offset) == b.get(offset) {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(range) if range.end == offset => range.end += 1,
+            _ => ranges.push(offset..offset + 1),
+        }
+    }
+    ranges
+}
+
+// An iNES file for tests: the bank counts come from the ROM sizes and the trainer flag
+// is set for a non empty `trainer`, on top of `flags6`
+#[cfg(test)]
+pub(crate) fn image(flags6: u8, trainer: &[u8], prg_rom: &[u8], chr_rom: &[u8]) -> Vec<u8> {
+    let mut bytes = b"NES\x1A".to_vec();
+    bytes.push((prg_rom.len() / PRG_ROM_BANK_SIZE) as u8);
+    bytes.push((chr_rom.len() / CHR_ROM_BANK_SIZE) as u8);
+    bytes.push(if trainer.is_empty() {
+        flags6
+    } else {
+        flags6 | 0x04
+    });
+    bytes.resize(HEADER_SIZE, 0x00);
+    bytes.extend(trainer);
+    bytes.extend(prg_rom);
+    bytes.extend(chr_rom);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_nrom() {
+        let bytes = image(
+            0x01,
+            &[],
+            &[0xEA; PRG_ROM_BANK_SIZE],
+            &[0x55; CHR_ROM_BANK_SIZE],
+        );
+
+        let rom = INes::new(bytes).unwrap();
+        assert_eq!(rom.prg_rom().len(), PRG_ROM_BANK_SIZE);
+        assert!(rom.prg_rom().iter().all(|byte| *byte == 0xEA));
+        assert_eq!(rom.chr_rom().len(), CHR_ROM_BANK_SIZE);
+        assert!(rom.trainer().is_none());
+        assert!(!rom.has_chr_ram());
+
+        let header = rom.header();
+        assert_eq!(header.mapper_number(), 0);
+        assert_eq!(header.prg_rom_size(), PRG_ROM_BANK_SIZE);
+        assert_eq!(header.chr_rom_size(), CHR_ROM_BANK_SIZE);
+        assert_eq!(
+            header.nametable_arrangement(),
+            NametableArrangement::Horizontal
+        );
+
+        assert!(INes::new(b"NES".to_vec()).is_err());
+        assert!(INes::new(vec![0x00; 32]).is_err());
+    }
+
+    #[test]
+    fn test_header() {
+        // MMC1 (mapper 1), horizontal arrangement, no CHR ROM
+        let header =
+            Header::new(b"NES\x1A\x08\x00\x10\x00\x00\x00\x00\x00\x00\x00\x00\x00").unwrap();
+        assert_eq!(header.mapper_number(), 1);
+        assert_eq!(header.prg_rom_size(), 128 * 1024);
+        assert_eq!(header.chr_rom_size(), 0);
+        assert_eq!(header.prg_ram_size(), 8 * 1024);
+        assert_eq!(header.tv_system(), None);
+        assert!(!header.four_screen());
+        assert_eq!(
+            header.nametable_arrangement(),
+            NametableArrangement::Vertical
+        );
+
+        // four-screen VRAM
+        let header = Header::new(b"NES         ").unwrap();
+        assert!(header.four_screen());
+
+        // mapper number split across flags 6 and 7
+        let header =
+            Header::new(b"NES\x1A\x01\x01\x41\x20\x00\x00\x00\x00\x00\x00\x00\x00").unwrap();
+        assert_eq!(header.mapper_number(), 0x24);
+        assert_eq!(
+            header.nametable_arrangement(),
+            NametableArrangement::Horizontal
+        );
+
+        // 4 banks of PRG RAM, then none at all
+        let header =
+            Header::new(b"NES\x1A\x01\x01\x00\x00\x04\x00\x00\x00\x00\x00\x00\x00").unwrap();
+        assert_eq!(header.prg_ram_size(), 32 * 1024);
+        let header =
+            Header::new(b"NES\x1A\x01\x01\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00").unwrap();
+        assert_eq!(header.tv_system(), Some(Region::Pal));
+        let header =
+            Header::new(b"NES\x1A\x01\x01\x00\x00\x04\x00\x10\x00\x00\x00\x00\x00").unwrap();
+        assert_eq!(header.prg_ram_size(), 0);
+    }
+
+    #[test]
+    fn test_truncated() {
+        // 2 PRG banks and 1 CHR bank declared, only 1 PRG bank present
+        let mut bytes = image(0x00, &[], &[0xEA; PRG_ROM_BANK_SIZE], &[]);
+        bytes[4] = 0x02;
+        bytes[5] = 0x01;
+        let err = INes::new(bytes.clone()).unwrap_err();
+        assert!(err.to_string().contains("truncated"), "{}", err);
+
+        // CHR ROM one byte short
+        bytes[4] = 0x01;
+        bytes.extend(vec![0x55; CHR_ROM_BANK_SIZE - 1]);
+        assert!(INes::new(bytes.clone()).is_err());
+        bytes.push(0x55);
+        assert!(INes::new(bytes.clone()).is_ok());
+
+        // the trainer comes on top
+        bytes[6] |= 0x04;
+        assert!(INes::new(bytes).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let trainer: Vec<u8> = (0..TRAINER_SIZE).map(|i| i as u8).collect();
+        let mut bytes = image(
+            0x13,
+            &trainer,
+            &[0xEA; 2 * PRG_ROM_BANK_SIZE],
+            &[0x55; CHR_ROM_BANK_SIZE],
+        );
+        // mapper high nibble, PAL, PRG RAM size and some garbage in the padding
+        bytes[7] = 0x20;
+        bytes[8] = 0x02;
+        bytes[9] = 0x01;
+        bytes[15] = 0xAA;
+        bytes.extend(b"trailer");
+
+        let rom = INes::new(bytes.clone()).unwrap();
+        assert_eq!(rom.header().mapper_number(), 0x21);
+        assert_eq!(rom.to_bytes(), bytes);
+        // CHR RAM isn't part of the file
+        let bytes = image(0x00, &[], &[0xEA; PRG_ROM_BANK_SIZE], &[]);
+        assert_eq!(INes::new(bytes.clone()).unwrap().to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_write_nestest() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("nestest.nes");
+        if !path.exists() {
+            eprintln!("nestest.nes not found, skipping");
+            return;
+        }
+        let rom = INes::from_file(&path).unwrap();
+        let out = std::env::temp_dir().join(format!("yane2-{}.nes", std::process::id()));
+        rom.write(&out).unwrap();
+        let written = std::fs::read(&out).unwrap();
+        std::fs::remove_file(&out).unwrap();
+        assert_eq!(written, std::fs::read(&path).unwrap());
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut prg_rom = vec![0xEA; PRG_ROM_BANK_SIZE];
+        let a = INes::new(image(0x00, &[], &prg_rom, &[0x55; CHR_ROM_BANK_SIZE])).unwrap();
+        prg_rom[0x0010] = 0x00;
+        prg_rom[0x0011] = 0x01;
+        prg_rom[0x3FFC] = 0x02;
+        // a different header: battery and mirroring
+        let b = INes::new(image(0x03, &[], &prg_rom, &[0x55; CHR_ROM_BANK_SIZE])).unwrap();
+
+        let diff = RomDiff::new(&a, &b);
+        assert_eq!(diff.prg_rom, [0x0010..0x0012, 0x3FFC..0x3FFD]);
+        assert!(diff.chr_rom.is_empty());
+        assert!(RomDiff::new(&a, &a).is_empty());
+
+        // a missing bank differs as a whole
+        let c = INes::new(image(0x00, &[], &prg_rom, &[])).unwrap();
+        let chr_rom = RomDiff::new(&b, &c).chr_rom;
+        assert_eq!(chr_rom.len(), 1);
+        assert_eq!(chr_rom[0], 0..CHR_ROM_BANK_SIZE);
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let bytes = image(0x00, &[], &[0xEA; PRG_ROM_BANK_SIZE], &[]);
+
+        let mut rom = INes::new(bytes).unwrap();
+        assert!(rom.chr_rom().is_empty());
+        assert!(rom.has_chr_ram());
+        let chr_ram = rom.chr_ram_mut().unwrap();
+        assert_eq!(chr_ram.len(), CHR_RAM_SIZE);
+        assert!(chr_ram.iter().all(|byte| *byte == 0x00));
+        chr_ram[0x1FFF] = 0x42;
+        assert_eq!(rom.chr_ram().unwrap()[0x1FFF], 0x42);
+    }
+}
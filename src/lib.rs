@@ -0,0 +1,7 @@
+// The emulator core, shared by the UI binary and the integration tests
+pub mod bus;
+pub mod cartridge;
+pub mod cpu;
+pub mod ines;
+pub mod region;
+pub mod symbols;
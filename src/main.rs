@@ -1,19 +1,378 @@
 use crate::miniquad::log;
+use clap::{Parser, Subcommand};
 use macroquad::prelude::*;
 use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::process::ExitCode;
 
-mod bus;
-mod cpu;
-
-use bus::Bus;
-use cpu::Cpu;
+use yane2::bus::Bus;
+use yane2::cartridge::Cartridge;
+use yane2::cpu::{self, Cpu, InterruptEvent, InterruptKind};
+use yane2::ines::{INes, RomDiff};
+use yane2::region::Region;
 
 const MAC_BORDER: f32 = 28.0;
 const FONT_SIZE: u16 = 16;
 const H_STEP: f32 = 1.0 + FONT_SIZE as f32;
 
-#[macroquad::main("Yane")]
-async fn main() {
+#[derive(Parser, Debug)]
+#[command(about = "Just another attempt at a Nes emulator")]
+struct Args {
+    /// Stack pointer to use after reset, as a hex byte (e.g. FD)
+    #[arg(long, value_parser = parse_hex_byte)]
+    sp: Option<u8>,
+
+    /// Number of instructions run by a turbo step (T key)
+    #[arg(long, default_value_t = 10)]
+    turbo_steps: usize,
+
+    /// Run the reset sequence up front, so the first step executes the instruction at the reset vector
+    #[arg(long)]
+    break_at_reset: bool,
+
+    /// Debugger labels to show in the disassembly (FCEUX .nl or Mesen .mlb)
+    #[arg(long)]
+    symbols: Option<std::path::PathBuf>,
+
+    /// Addresses shown in the code pane, as hex START-END (default: the PRG ROM window)
+    #[arg(long, value_parser = parse_hex_range)]
+    disasm_range: Option<RangeInclusive<u16>>,
+
+    /// Run without opening a window, then print the CPU state
+    #[arg(long)]
+    headless: bool,
+
+    /// Number of CPU cycles run in headless mode
+    #[arg(long, default_value_t = 100_000)]
+    cycles: u64,
+
+    /// Write the trace of the first N instructions after the reset to --boot-trace-file, then exit
+    #[arg(long, value_name = "N")]
+    boot_trace: Option<usize>,
+
+    /// Output of --boot-trace
+    #[arg(long, default_value = "boot.trace")]
+    boot_trace_file: std::path::PathBuf,
+
+    /// Write the CPU state as JSON to this file after the headless run
+    #[arg(long, value_name = "FILE")]
+    dump_state: Option<std::path::PathBuf>,
+
+    /// Memory included in --dump-state, as hex START-END
+    #[arg(long, value_parser = parse_hex_range)]
+    dump_range: Option<RangeInclusive<u16>>,
+
+    /// Print the interrupts taken, optionally only some kinds (e.g. --log-interrupts=nmi,irq)
+    #[arg(
+        long,
+        value_name = "KINDS",
+        value_delimiter = ',',
+        num_args = 0..,
+        require_equals = true
+    )]
+    log_interrupts: Option<Vec<InterruptKind>>,
+
+    /// Print one --log line every N instructions, to keep the trace of long runs small
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    trace_sampling: usize,
+
+    /// TV system (ntsc, pal or dendy), instead of the one from the ROM header
+    #[arg(long)]
+    region: Option<Region>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print a fixed width disassembly of the test program and exit
+    Disasm {
+        /// First address to disassemble, in hex
+        #[arg(long, value_parser = parse_hex_word, default_value = "8000")]
+        start: u16,
+        /// Last address to disassemble, in hex
+        #[arg(long, value_parser = parse_hex_word, default_value = "801B")]
+        end: u16,
+        /// Annotate each instruction with its cycle count, e.g. `; 4(+1)`
+        #[arg(long)]
+        cycles: bool,
+    },
+    /// Run an NROM test ROM (e.g. nestest.nes) instead of the built-in program
+    Nestest {
+        /// Path to the iNES file
+        #[arg(default_value = "nestest.nes")]
+        rom: std::path::PathBuf,
+        /// Start address in hex instead of the reset vector (C000 for the nestest automation mode)
+        #[arg(long, value_parser = parse_hex_word)]
+        start: Option<u16>,
+        /// Print a nestest.log style line for each instruction run
+        #[arg(long)]
+        log: bool,
+    },
+    /// Print where the PRG ROM and CHR ROM of two iNES files differ and exit
+    Compare {
+        a: std::path::PathBuf,
+        b: std::path::PathBuf,
+    },
+    /// Print the layout of the CPU address space and exit
+    Map {
+        /// iNES file plugged as the cartridge, instead of the built-in program
+        rom: Option<std::path::PathBuf>,
+    },
+}
+
+fn parse_hex_byte(s: &str) -> Result<u8, String> {
+    let digits = s.trim_start_matches('$').trim_start_matches("0x");
+    u8::from_str_radix(digits, 16).map_err(|_| format!("'{}' is not a byte in hex (00-FF)", s))
+}
+
+fn parse_hex_word(s: &str) -> Result<u16, String> {
+    let digits = s.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(digits, 16)
+        .map_err(|_| format!("'{}' is not an address in hex (0000-FFFF)", s))
+}
+
+fn parse_hex_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| format!("'{}' is not a range START-END", s))?;
+    let (start, end) = (parse_hex_word(start)?, parse_hex_word(end)?);
+    if start > end {
+        return Err(format!("'{}' is an empty range", s));
+    }
+    Ok(start..=end)
+}
+
+// Decoding the whole address space is slow and mostly lists empty RAM
+fn disasm_range(args: &Args, bus: &Bus) -> RangeInclusive<u16> {
+    args.disasm_range
+        .clone()
+        .unwrap_or_else(|| bus.code_region())
+}
+
+// Register overrides from the command line, applied after reset
+fn apply_overrides(args: &Args, cpu: &mut Cpu) {
+    if let Some(sp) = args.sp {
+        cpu.core.sp = sp;
+    }
+    if let Some(Command::Nestest {
+        start: Some(pc), ..
+    }) = args.command
+    {
+        cpu.core.pc = pc;
+    }
+}
+
+// Trace the instruction about to run when it's sampled. Nothing while the reset
+// sequence is pending: no instruction is fetched until it's over.
+fn trace(cpu: &Cpu, log: bool) {
+    if log && cpu.complete() && cpu.trace_sampled() {
+        println!("{}", cpu.trace_line());
+    }
+}
+
+// Interrupt log lines kept by the --log-interrupts filter, an empty one keeps them all
+fn interrupt_lines(events: &[InterruptEvent], filter: &[InterruptKind]) -> Vec<String> {
+    events
+        .iter()
+        .filter(|event| filter.is_empty() || filter.contains(&event.kind))
+        .map(|event| {
+            format!(
+                "{} from ${:>04X} through ${:>04X} CYC:{}",
+                event.kind, event.pc, event.vector, event.cycle
+            )
+        })
+        .collect()
+}
+
+fn log_interrupts(cpu: &mut Cpu, args: &Args) {
+    if let Some(filter) = &args.log_interrupts {
+        for line in interrupt_lines(&cpu.take_interrupt_log(), filter) {
+            println!("{}", line);
+        }
+    }
+}
+
+fn dump_state(
+    cpu: &Cpu,
+    path: &std::path::Path,
+    memory: Option<RangeInclusive<u16>>,
+) -> eyre::Result<()> {
+    let json = serde_json::to_string_pretty(&cpu.snapshot(memory))?;
+    std::fs::write(path, json + "\n")
+        .map_err(|err| eyre::eyre!("Failed to write {}: {}", path.display(), err))
+}
+
+fn boot(args: &Args, cpu: &mut Cpu) {
+    cpu.power_on();
+    apply_overrides(args, cpu);
+
+    if args.break_at_reset {
+        // only burns the reset cycles: no instruction is fetched until cycles reach 0
+        while !cpu.complete() {
+            cpu.clock();
+        }
+    }
+}
+
+// Drive the CPU without any window, for scripts and CI: whole instructions until at
+// least `cycles` have elapsed, the CPU halts (guard violation or jam) or `before_step`
+// returns false. `before_step` sees the CPU ahead of each step. Returns the number of
+// cycles run.
+fn run_headless(cpu: &mut Cpu, cycles: u64, mut before_step: impl FnMut(&mut Cpu) -> bool) -> u64 {
+    let mut elapsed = 0;
+    while elapsed < cycles && !cpu.halted() && before_step(cpu) {
+        elapsed += cpu.step() as u64;
+    }
+    elapsed
+}
+
+// Trace of the first `count` instructions run from the reset, the reset sequence
+// itself isn't an instruction. Fewer lines when the CPU halts before.
+fn boot_trace(cpu: &mut Cpu, count: usize) -> Vec<String> {
+    let mut lines = Vec::with_capacity(count);
+    run_headless(cpu, u64::MAX, |cpu| {
+        if cpu.complete() {
+            if lines.len() == count {
+                return false;
+            }
+            lines.push(cpu.trace_line());
+        }
+        true
+    });
+    lines
+}
+
+// Everything the UI and the headless mode share: the program or ROM, the reset and
+// the symbols. None when a command ran and there is nothing left to do.
+fn setup(args: &Args) -> eyre::Result<Option<Cpu>> {
+    if let Some(Command::Compare { a, b }) = &args.command {
+        let (a, b) = (INes::from_file(a)?, INes::from_file(b)?);
+        for line in diff_lines(&RomDiff::new(&a, &b)) {
+            println!("{}", line);
+        }
+        return Ok(None);
+    }
+
+    let mut bus = Bus::new();
+    let mut tv_system = None;
+
+    let rom = match &args.command {
+        Some(Command::Nestest { rom, .. }) => Some(rom),
+        Some(Command::Map { rom }) => rom.as_ref(),
+        _ => None,
+    };
+    if let Some(rom) = rom {
+        let rom = INes::from_file(rom)?;
+        nestest(&rom, &mut bus)?;
+        tv_system = rom.header().tv_system();
+    } else {
+        setup_ram(&mut bus);
+    }
+
+    let mut cpu = Cpu::new(bus);
+    cpu.set_interrupt_logging(args.log_interrupts.is_some());
+    cpu.set_trace_sampling(args.trace_sampling);
+    // the command line wins over the header, then the build default
+    cpu.set_region(args.region.or(tv_system).unwrap_or_default());
+
+    boot(args, &mut cpu);
+
+    if let Some(path) = &args.symbols {
+        cpu.load_symbols_file(path)
+            .map_err(|err| eyre::eyre!(err))?;
+    }
+
+    if let Some(Command::Disasm { start, end, cycles }) = args.command {
+        print!("{}", cpu.disassemble_text(start, end, cycles));
+        return Ok(None);
+    }
+
+    if let Some(Command::Map { .. }) = args.command {
+        for line in map_lines(cpu.bus()) {
+            println!("{}", line);
+        }
+        return Ok(None);
+    }
+
+    Ok(Some(cpu))
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let mut cpu = match setup(&args) {
+        Ok(Some(cpu)) => cpu,
+        Ok(None) => return ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{:#}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if let Some(count) = args.boot_trace {
+        let lines = boot_trace(&mut cpu, count);
+        let text: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+        if let Err(err) = std::fs::write(&args.boot_trace_file, text) {
+            eprintln!(
+                "Failed to write {}: {}",
+                args.boot_trace_file.display(),
+                err
+            );
+            return ExitCode::FAILURE;
+        }
+        println!(
+            "{} instructions traced to {}",
+            lines.len(),
+            args.boot_trace_file.display()
+        );
+        return ExitCode::SUCCESS;
+    }
+
+    if args.headless {
+        let log = matches!(args.command, Some(Command::Nestest { log: true, .. }));
+        let elapsed = run_headless(&mut cpu, args.cycles, |cpu| {
+            log_interrupts(cpu, &args);
+            trace(cpu, log);
+            true
+        });
+        log_interrupts(&mut cpu, &args);
+        let mut status = ExitCode::SUCCESS;
+        if let Some(path) = &args.dump_state {
+            if let Err(err) = dump_state(&cpu, path, args.dump_range.clone()) {
+                eprintln!("{:#}", err);
+                status = ExitCode::FAILURE;
+            }
+        }
+        println!("{}", cpu.trace_line());
+        println!(
+            "{} cycles, {:.3}ms on {}",
+            elapsed,
+            cpu.emulated_time(elapsed) * 1000.0,
+            cpu.region()
+        );
+        // scripts and CI can tell a run that went wrong from one that ran out of cycles
+        if let Some(violation) = cpu.guard_violation() {
+            eprintln!("{:?}", violation);
+            status = ExitCode::FAILURE;
+        }
+        if cpu.jammed() {
+            let pc = cpu.core.pc;
+            eprintln!(
+                "JAM: opcode ${:>02X} at PC ${:>04X}",
+                cpu.bus().peek(pc),
+                pc
+            );
+            status = ExitCode::FAILURE;
+        }
+        return status;
+    }
+
+    macroquad::Window::new("Yane", ui(args, cpu));
+    ExitCode::SUCCESS
+}
+
+async fn ui(args: Args, mut cpu: Cpu) {
     request_new_screen_size(1024.0, 768.0 + MAC_BORDER);
 
     next_frame().await; // acknowledge new screen size
@@ -28,15 +387,8 @@ async fn main() {
         screen_height()
     );
 
-    let mut bus = Bus::new();
-
-    setup_ram(&mut bus);
-
-    let mut cpu = Cpu::new(bus);
-
-    cpu.reset();
-
-    let disas = cpu.disassemble(0x0000, 0xFFFF);
+    let code_range = disasm_range(&args, cpu.bus());
+    let disas = cpu.disassemble(*code_range.start(), *code_range.end());
 
     // let image = Image::gen_image_color(w as u16, h as u16, RED);
     // let texture = Texture2D::from_image(&image);
@@ -53,17 +405,24 @@ async fn main() {
 
     // just for test
 
+    let log = matches!(args.command, Some(Command::Nestest { log: true, .. }));
+    let mut show_map = false;
+    let mut show_opcode = false;
+
     loop {
         if is_key_down(KeyCode::Q) || is_key_down(KeyCode::Escape) {
             break;
         }
 
         if is_key_pressed(KeyCode::Space) {
-            loop {
-                cpu.clock();
-                if cpu.complete() {
-                    break;
-                }
+            trace(&cpu, log);
+            cpu.step();
+        }
+
+        if is_key_pressed(KeyCode::T) {
+            for _ in 0..args.turbo_steps {
+                trace(&cpu, log);
+                cpu.step();
             }
         }
 
@@ -71,7 +430,27 @@ async fn main() {
             cpu.reset()
         }
 
-        // TODO: IRQ / NMI
+        if is_key_pressed(KeyCode::M) {
+            show_map = !show_map
+        }
+
+        if is_key_pressed(KeyCode::O) {
+            show_opcode = !show_opcode
+        }
+
+        if is_key_pressed(KeyCode::Z) {
+            cpu.bus_mut().fill(0x0000..=0x00FF, 0x00)
+        }
+
+        if is_key_pressed(KeyCode::N) {
+            cpu.nmi()
+        }
+
+        if is_key_pressed(KeyCode::I) {
+            cpu.irq()
+        }
+
+        log_interrupts(&mut cpu, &args);
 
         clear_background(BLUE);
 
@@ -82,7 +461,7 @@ async fn main() {
             10.0,
             MAC_BORDER + 10.0,
             0x0000,
-            &cpu.bus().read().expect("Failed to get bus"),
+            cpu.bus(),
             16,
             16,
             &font_params,
@@ -92,22 +471,31 @@ async fn main() {
             10.0,
             20.0 * H_STEP + 10.0,
             0x8000,
-            &cpu.bus().read().expect("Failed to get bus"),
+            cpu.bus(),
             16,
             16,
             &font_params,
         );
 
-        draw_cpu(600.0, MAC_BORDER + 10.0, &cpu, &font_params).await;
-        draw_code(
-            600.0,
-            MAC_BORDER + 10.0 + 7.0 * H_STEP,
-            cpu.core.pc,
-            26,
-            &disas,
-            &font_params,
-        )
-        .await;
+        draw_cpu(600.0, MAC_BORDER + 10.0, &cpu, show_opcode, &font_params).await;
+        if show_map {
+            draw_map(
+                600.0,
+                MAC_BORDER + 10.0 + 10.0 * H_STEP,
+                cpu.bus(),
+                &font_params,
+            );
+        } else {
+            draw_code(
+                600.0,
+                MAC_BORDER + 10.0 + 10.0 * H_STEP,
+                cpu.core.pc,
+                26,
+                &disas,
+                &font_params,
+            )
+            .await;
+        }
 
         draw_text_ex(
             "SPACE = Step Instruction    R = RESET    I = IRQ    N = NMI",
@@ -115,6 +503,25 @@ async fn main() {
             700.0,
             font_params.clone(),
         );
+        draw_text_ex(
+            &format!(
+                "T = Step {} Instructions    M = Memory map    O = Opcode    Z = Clear zero page",
+                args.turbo_steps
+            ),
+            40.0,
+            700.0 + H_STEP,
+            font_params.clone(),
+        );
+        draw_text_ex(
+            &format!(
+                "Disassembly ${:>04X}-${:>04X}",
+                code_range.start(),
+                code_range.end()
+            ),
+            40.0,
+            700.0 + 2.0 * H_STEP,
+            font_params.clone(),
+        );
 
         next_frame().await
     }
@@ -127,11 +534,9 @@ fn setup_ram(bus: &mut Bus) {
     let program =
         "A2 0A 8E 00 00 A2 03 8E 01 00 AC 00 00 A9 00 18 6D 01 00 88 D0 FA 8D 02 00 EA EA EA"
             .split(' ');
-    let mut addr = 0x8000;
-    for s in program {
+    for (addr, s) in (0x8000..).zip(program) {
         let byte = u8::from_str_radix(s, 16).unwrap();
         bus.ram[addr] = byte;
-        addr += 1;
     }
 
     // Set Reset Vector
@@ -139,7 +544,13 @@ fn setup_ram(bus: &mut Bus) {
     bus.ram[0xFFFD] = 0x80;
 }
 
-async fn draw_cpu(x: f32, y: f32, cpu: &Cpu, font_params: &TextParams<'_>) {
+// Plug a mapper 0 cartridge: a single 16KB bank shows at both $8000 and $C000.
+// The reset vector comes with it, from the end of the ROM.
+fn nestest(rom: &INes, bus: &mut Bus) -> eyre::Result<()> {
+    bus.attach_cartridge(Cartridge::new(rom)?)
+}
+
+async fn draw_cpu(x: f32, y: f32, cpu: &Cpu, show_opcode: bool, font_params: &TextParams<'_>) {
     let red = TextParams {
         color: RED,
         ..font_params.clone()
@@ -186,16 +597,8 @@ async fn draw_cpu(x: f32, y: f32, cpu: &Cpu, font_params: &TextParams<'_>) {
         },
     );
     pos += xstep;
-    draw_text_ex(
-        "B",
-        pos,
-        y,
-        if cpu.core.get_flag(cpu::Flags::B) {
-            green.clone()
-        } else {
-            red.clone()
-        },
-    );
+    // B only exists in the status byte pushed on the stack
+    draw_text_ex("B", pos, y, font_params.clone());
     pos += xstep;
     draw_text_ex(
         "D",
@@ -244,7 +647,7 @@ async fn draw_cpu(x: f32, y: f32, cpu: &Cpu, font_params: &TextParams<'_>) {
     pos = y + H_STEP;
 
     draw_text_ex(
-        &format!("PC: ${:>04X}", cpu.core.pc),
+        &format!("PC: ${:>04X}    {}", cpu.core.pc, cpu.region()),
         x,
         pos,
         font_params.clone(),
@@ -277,6 +680,56 @@ async fn draw_cpu(x: f32, y: f32, cpu: &Cpu, font_params: &TextParams<'_>) {
         pos,
         font_params.clone(),
     );
+    if show_opcode {
+        let next = cpu.bus().peek(cpu.core.pc);
+        pos += H_STEP;
+        draw_text_ex(
+            &format!(
+                "Next: ${:>02X} {}  Last: ${:>02X} {}",
+                next,
+                cpu.mnemonic(next),
+                cpu.current_opcode(),
+                cpu.current_mnemonic()
+            ),
+            x,
+            pos,
+            font_params.clone(),
+        );
+    }
+    if let Some(addr) = cpu.effective_address() {
+        pos += H_STEP;
+        draw_text_ex(
+            &format!("Eff. Addr: ${:>04X}", addr),
+            x,
+            pos,
+            font_params.clone(),
+        );
+    }
+    if let Some(violation) = cpu.guard_violation() {
+        pos += H_STEP;
+        draw_text_ex(
+            &format!(
+                "GUARD: {:?} ${:>04X} at PC ${:>04X}",
+                violation.kind, violation.addr, violation.pc
+            ),
+            x,
+            pos,
+            red.clone(),
+        );
+    }
+    if cpu.jammed() {
+        pos += H_STEP;
+        draw_text_ex(
+            &format!(
+                "JAM: opcode ${:>02X} at PC ${:>04X}",
+                cpu.current_opcode(),
+                cpu.core.pc
+            ),
+            x,
+            pos,
+            red.clone(),
+        );
+    }
 }
 
 fn draw_ram(
@@ -293,7 +746,7 @@ fn draw_ram(
     for _ in 0..rows {
         let mut line = format!("${:>04X}:", addr);
         for _ in 0..columns {
-            line = format!("{} {:>02X}", line, bus.read(addr));
+            line = format!("{} {:>02X}", line, bus.peek(addr));
             addr += 1;
         }
         draw_text_ex(&line, x, pos, font_params.clone());
@@ -301,6 +754,50 @@ fn draw_ram(
     }
 }
 
+fn diff_lines(diff: &RomDiff) -> Vec<String> {
+    if diff.is_empty() {
+        return vec!["PRG ROM and CHR ROM are identical".into()];
+    }
+    let mut lines = Vec::new();
+    for (name, ranges) in [("PRG ROM", &diff.prg_rom), ("CHR ROM", &diff.chr_rom)] {
+        let count: usize = ranges.iter().map(|range| range.len()).sum();
+        lines.push(format!("{}: {} bytes differ", name, count));
+        for range in ranges {
+            let plural = if range.len() == 1 { "" } else { "s" };
+            lines.push(format!(
+                "  ${:>05X}-${:>05X}: {} byte{}",
+                range.start,
+                range.end - 1,
+                range.len(),
+                plural
+            ));
+        }
+    }
+    lines
+}
+
+fn map_lines(bus: &Bus) -> Vec<String> {
+    bus.describe_map()
+        .into_iter()
+        .map(|(range, description)| {
+            format!(
+                "${:>04X}-${:>04X}: {}",
+                range.start(),
+                range.end(),
+                description
+            )
+        })
+        .collect()
+}
+
+fn draw_map(x: f32, y: f32, bus: &Bus, font_params: &TextParams<'_>) {
+    let mut pos = y;
+    for line in map_lines(bus) {
+        draw_text_ex(&line, x, pos, font_params.clone());
+        pos += H_STEP;
+    }
+}
+
 async fn draw_code(
     x: f32,
     y: f32,
@@ -384,3 +881,317 @@ async fn draw_code(
     //     }
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sp_override() {
+        let args = Args::try_parse_from(["yane2", "--sp", "80"]).unwrap();
+        let mut cpu = Cpu::new(Bus::new());
+        cpu.power_on();
+        assert_eq!(cpu.core.sp, 0xFD);
+        apply_overrides(&args, &mut cpu);
+        assert_eq!(cpu.core.sp, 0x80);
+
+        let args = Args::try_parse_from(["yane2", "--sp", "$1F"]).unwrap();
+        assert_eq!(args.sp, Some(0x1F));
+        let args = Args::try_parse_from(["yane2"]).unwrap();
+        assert_eq!(args.sp, None);
+    }
+
+    #[test]
+    fn test_break_at_reset() {
+        let args = Args::try_parse_from(["yane2", "--break-at-reset"]).unwrap();
+        let mut bus = Bus::new();
+        setup_ram(&mut bus);
+        let mut cpu = Cpu::new(bus);
+
+        boot(&args, &mut cpu);
+        assert!(cpu.complete());
+        assert_eq!(cpu.instruction_count(), 0);
+        assert_eq!(cpu.core.pc, 0x8000);
+
+        // the first step runs the first instruction: LDX #$0A
+        assert_eq!(cpu.step(), 2);
+        assert_eq!(cpu.instruction_count(), 1);
+        assert_eq!(cpu.core.x, 0x0A);
+    }
+
+    // One line per instruction with the state before it runs, nestest style
+    fn trace_test0() -> String {
+        let mut bus = Bus::new();
+        setup_ram(&mut bus);
+        let mut cpu = Cpu::new(bus);
+        cpu.power_on();
+        cpu.step_n(1); // reset sequence
+
+        let mut trace = String::new();
+        // the program ends with 3 NOPs at $8019..=$801B
+        while cpu.core.pc <= 0x801B {
+            let core = &cpu.core;
+            let opcode = cpu.bus().peek(core.pc);
+            trace.push_str(&format!(
+                "{:>04X}  {:>02X}  {}  A:{:>02X} X:{:>02X} Y:{:>02X} P:{:>02X} SP:{:>02X} CYC:{}\n",
+                core.pc,
+                opcode,
+                cpu.mnemonic(opcode),
+                core.a,
+                core.x,
+                core.y,
+                core.status_byte(),
+                core.sp,
+                cpu.total_cycles()
+            ));
+            cpu.step_n(1);
+        }
+        trace
+    }
+
+    // Set YANE_BLESS=1 to regenerate the golden file after an intended change
+    #[test]
+    fn test_golden_trace_test0() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/traces/test0.trace");
+        let trace = trace_test0();
+
+        if std::env::var_os("YANE_BLESS").is_some() || !std::path::Path::new(path).exists() {
+            std::fs::create_dir_all(std::path::Path::new(path).parent().unwrap()).unwrap();
+            std::fs::write(path, &trace).unwrap();
+            return;
+        }
+
+        let golden = std::fs::read_to_string(path).unwrap();
+        for (number, (expected, actual)) in golden.lines().zip(trace.lines()).enumerate() {
+            assert_eq!(actual, expected, "trace mismatch at line {}", number + 1);
+        }
+        assert_eq!(trace, golden);
+    }
+
+    #[test]
+    fn test_disasm_range() {
+        let mut bus = Bus::new();
+        setup_ram(&mut bus);
+
+        let args = Args::try_parse_from(["yane2"]).unwrap();
+        assert_eq!(disasm_range(&args, &bus), 0x8000..=0xFFFF);
+        let args = Args::try_parse_from(["yane2", "--disasm-range", "8000-801B"]).unwrap();
+        let range = disasm_range(&args, &bus);
+        assert_eq!(range, 0x8000..=0x801B);
+        assert!(Args::try_parse_from(["yane2", "--disasm-range", "801B-8000"]).is_err());
+        assert!(Args::try_parse_from(["yane2", "--disasm-range", "8000"]).is_err());
+
+        let cpu = Cpu::new(bus);
+        let disas = cpu.disassemble(*range.start(), *range.end());
+        assert_eq!(disas.keys().next(), Some(&0x8000));
+        assert_eq!(disas.keys().last(), Some(&0x801B));
+        assert_eq!(disas.len(), 14);
+    }
+
+    #[test]
+    fn test_map_command() {
+        let args = Args::try_parse_from(["yane2", "map"]).unwrap();
+        assert!(matches!(args.command, Some(Command::Map { rom: None })));
+        // the command prints and leaves nothing to run
+        assert!(setup(&args).unwrap().is_none());
+
+        let mut bus = Bus::new();
+        setup_ram(&mut bus);
+        assert_eq!(
+            map_lines(&bus),
+            [
+                "$0000-$1FFF: RAM (2KB, mirrored)",
+                "$2000-$5FFF: RAM (flat)",
+                "$6000-$7FFF: PRG RAM (8KB)",
+                "$8000-$FFFF: RAM (flat)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        let diff = RomDiff {
+            prg_rom: vec![0x0010..0x0012, 0x3FFC..0x3FFD],
+            chr_rom: vec![],
+        };
+        assert_eq!(
+            diff_lines(&diff),
+            [
+                "PRG ROM: 3 bytes differ",
+                "  $00010-$00011: 2 bytes",
+                "  $03FFC-$03FFC: 1 byte",
+                "CHR ROM: 0 bytes differ",
+            ]
+        );
+        let args = Args::try_parse_from(["yane2", "compare", "a.nes", "b.nes"]).unwrap();
+        assert!(matches!(args.command, Some(Command::Compare { .. })));
+    }
+
+    #[test]
+    fn test_setup_errors() {
+        // failures reach main, which exits with a failure status
+        let args = Args::try_parse_from(["yane2", "compare", "missing_a.nes", "b.nes"]).unwrap();
+        assert!(setup(&args).is_err());
+        let args = Args::try_parse_from(["yane2", "map", "missing.nes"]).unwrap();
+        assert!(setup(&args).is_err());
+        let args = Args::try_parse_from(["yane2", "--symbols", "missing.sym"]).unwrap();
+        assert!(setup(&args).is_err());
+    }
+
+    #[test]
+    fn test_trace_line() {
+        // test0 is in RAM, the comparison with nestest.log lives in tests/nestest.rs
+        let args = Args::try_parse_from(["yane2"]).unwrap();
+        let mut cpu = setup(&args).unwrap().unwrap();
+        cpu.step_n(1); // reset sequence
+
+        let expected = [
+            "8000  A2 0A     LDX #$0A                        A:00 X:00 Y:00 P:24 SP:FD CYC:7",
+            "8002  8E 00 00  STX $0000 = 00                  A:00 X:0A Y:00 P:24 SP:FD CYC:9",
+            "8005  A2 03     LDX #$03                        A:00 X:0A Y:00 P:24 SP:FD CYC:13",
+        ];
+        for expected in expected {
+            let line = cpu.trace_line();
+            // the registers always start at the same column
+            assert_eq!(line.find("A:"), Some(48), "{}", line);
+            assert_eq!(line, expected);
+            cpu.step_n(1);
+        }
+    }
+
+    #[test]
+    fn test_headless_test0() {
+        let args = Args::try_parse_from(["yane2", "--headless", "--cycles", "120"]).unwrap();
+        assert!(args.headless);
+        let mut cpu = setup(&args).unwrap().unwrap();
+
+        // the reset sequence, then the whole program up to the trailing NOPs
+        assert_eq!(run_headless(&mut cpu, args.cycles, |_| true), 120);
+        assert_eq!(cpu.core.pc, 0x8019);
+        let bus = cpu.bus();
+        // 10 * 3
+        assert_eq!(bus.peek(0x0000), 0x0A);
+        assert_eq!(bus.peek(0x0001), 0x03);
+        assert_eq!(bus.peek(0x0002), 0x1E);
+    }
+
+    #[test]
+    fn test_headless_default_cycles() {
+        // test0 runs off its NOPs, BRKs through the zeroed vector to $0000 and jams on
+        // the 3 it stored at $0001 long before the default budget
+        let args = Args::try_parse_from(["yane2", "--headless"]).unwrap();
+        let mut cpu = setup(&args).unwrap().unwrap();
+        assert!(run_headless(&mut cpu, args.cycles, |_| true) < args.cycles);
+        assert!(cpu.jammed());
+        assert!(cpu.guard_violation().is_none());
+        let bus = cpu.bus();
+        assert_eq!(bus.peek(cpu.core.pc), 0x03);
+        assert_eq!(bus.peek(0x0002), 0x1E);
+    }
+
+    #[test]
+    fn test_boot_trace() {
+        let args = Args::try_parse_from(["yane2", "--boot-trace", "5"]).unwrap();
+        assert_eq!(args.boot_trace, Some(5));
+        let mut cpu = setup(&args).unwrap().unwrap();
+
+        let lines = boot_trace(&mut cpu, 5);
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("8000  A2 0A     LDX #$0A"));
+        assert!(lines[4].starts_with("800A  AC 00 00  LDY $0000"));
+        // stopped right before the 6th one
+        assert_eq!(cpu.instruction_count(), 5);
+
+        // test0 jams after a few dozen instructions
+        let mut cpu = setup(&args).unwrap().unwrap();
+        assert!(boot_trace(&mut cpu, 1000).len() < 1000);
+    }
+
+    #[test]
+    fn test_log_interrupts() {
+        let args = Args::try_parse_from(["yane2", "--log-interrupts=nmi,irq"]).unwrap();
+        let filter = args.log_interrupts.clone().unwrap();
+        assert_eq!(filter, [InterruptKind::Nmi, InterruptKind::Irq]);
+        // no value: every kind
+        let all = Args::try_parse_from(["yane2", "--log-interrupts"]).unwrap();
+        assert_eq!(all.log_interrupts, Some(vec![]));
+        assert!(Args::try_parse_from(["yane2", "--log-interrupts=reset"]).is_err());
+        // the subcommand isn't taken as a filter
+        let args_nestest = Args::try_parse_from(["yane2", "--log-interrupts", "nestest"]).unwrap();
+        assert!(matches!(
+            args_nestest.command,
+            Some(Command::Nestest { .. })
+        ));
+
+        // test0 BRKs once it runs off its NOPs
+        let mut cpu = setup(&args).unwrap().unwrap();
+        run_headless(&mut cpu, args.cycles, |_| true);
+        let events = cpu.take_interrupt_log();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, InterruptKind::Brk);
+        assert_eq!(interrupt_lines(&events, &filter), Vec::<String>::new());
+        let lines = interrupt_lines(&events, &[]);
+        assert!(lines[0].starts_with("BRK from $"), "{}", lines[0]);
+        assert!(lines[0].contains("through $FFFE"), "{}", lines[0]);
+    }
+
+    #[test]
+    fn test_dump_state() {
+        let path = std::env::temp_dir().join(format!("yane2-state-{}.json", std::process::id()));
+        let args = Args::try_parse_from([
+            "yane2",
+            "--headless",
+            "--cycles",
+            "120",
+            "--dump-state",
+            path.to_str().unwrap(),
+            "--dump-range",
+            "0000-0002",
+        ])
+        .unwrap();
+        let mut cpu = setup(&args).unwrap().unwrap();
+        run_headless(&mut cpu, args.cycles, |_| true);
+        dump_state(&cpu, args.dump_state.as_ref().unwrap(), args.dump_range).unwrap();
+
+        let json = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let state: serde_json::Value = serde_json::from_str(&json).unwrap();
+        // 10 * 3 in A, Y counted down to 0
+        assert_eq!(state["a"], 0x1E);
+        assert_eq!(state["x"], 0x03);
+        assert_eq!(state["y"], 0x00);
+        assert_eq!(state["sp"], 0xFD);
+        assert_eq!(state["pc"], 0x8019);
+        assert_eq!(state["flags"]["Z"], true);
+        assert_eq!(state["flags"]["I"], true);
+        assert_eq!(state["flags"]["C"], false);
+        // the reset sequence is part of the run
+        assert_eq!(state["cycles"], 120);
+        assert_eq!(state["jammed"], false);
+        assert_eq!(state["memory"]["start"], 0x0000);
+        assert_eq!(
+            state["memory"]["bytes"],
+            serde_json::json!([0x0A, 0x03, 0x1E])
+        );
+    }
+
+    #[test]
+    fn test_region() {
+        let args = Args::try_parse_from(["yane2"]).unwrap();
+        assert_eq!(setup(&args).unwrap().unwrap().region(), Region::default());
+
+        let args = Args::try_parse_from(["yane2", "--region", "pal"]).unwrap();
+        let cpu = setup(&args).unwrap().unwrap();
+        assert_eq!(cpu.region(), Region::Pal);
+        assert_eq!((cpu.emulated_time(1_662_607) * 1000.0).round(), 1000.0);
+
+        assert!(Args::try_parse_from(["yane2", "--region", "secam"]).is_err());
+    }
+
+    #[test]
+    fn test_sp_override_out_of_range() {
+        assert!(Args::try_parse_from(["yane2", "--sp", "100"]).is_err());
+        assert!(Args::try_parse_from(["yane2", "--sp", "-1"]).is_err());
+        assert!(Args::try_parse_from(["yane2", "--sp", "zz"]).is_err());
+    }
+}
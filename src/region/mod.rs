@@ -0,0 +1,148 @@
+/// Region dependent timings, see https://www.nesdev.org/wiki/Cycle_reference_chart
+use std::fmt;
+use std::str::FromStr;
+
+// 2C02 colors as RGB, from https://github.com/OneLoneCoder/olcNES
+#[rustfmt::skip]
+const NTSC_PALETTE: [(u8, u8, u8); 64] = [
+    (84, 84, 84), (0, 30, 116), (8, 16, 144), (48, 0, 136), (68, 0, 100), (92, 0, 48), (84, 4, 0), (60, 24, 0),
+    (32, 42, 0), (8, 58, 0), (0, 64, 0), (0, 60, 0), (0, 50, 60), (0, 0, 0), (0, 0, 0), (0, 0, 0),
+    (152, 150, 152), (8, 76, 196), (48, 50, 236), (92, 30, 228), (136, 20, 176), (160, 20, 100), (152, 34, 32), (120, 60, 0),
+    (84, 90, 0), (40, 114, 0), (8, 124, 0), (0, 118, 40), (0, 102, 120), (0, 0, 0), (0, 0, 0), (0, 0, 0),
+    (236, 238, 236), (76, 154, 236), (120, 124, 236), (176, 98, 236), (228, 84, 236), (236, 88, 180), (236, 106, 100), (212, 136, 32),
+    (160, 170, 0), (116, 196, 0), (76, 208, 32), (56, 204, 108), (56, 180, 204), (60, 60, 60), (0, 0, 0), (0, 0, 0),
+    (236, 238, 236), (168, 204, 236), (188, 188, 236), (212, 178, 236), (236, 174, 236), (236, 174, 212), (236, 180, 176), (228, 196, 144),
+    (204, 210, 120), (180, 222, 120), (168, 226, 144), (152, 226, 180), (160, 214, 228), (160, 162, 160), (0, 0, 0), (0, 0, 0),
+];
+
+// The 2C07 shows the same base colors, the PAL PPU differs in its emphasis bits
+// (red and green swapped) and in how grays are decoded
+const PAL_PALETTE: [(u8, u8, u8); 64] = NTSC_PALETTE;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Default for Region {
+    // The default region is selected at compile time with the `pal` / `dendy` features
+    fn default() -> Self {
+        if cfg!(feature = "pal") {
+            Region::Pal
+        } else if cfg!(feature = "dendy") {
+            Region::Dendy
+        } else {
+            Region::Ntsc
+        }
+    }
+}
+
+impl Region {
+    // Master clock frequency, in Hz
+    pub fn master_clock(&self) -> f64 {
+        match self {
+            Region::Ntsc => 236.25e6 / 11.0,
+            Region::Pal | Region::Dendy => 26.601712e6,
+        }
+    }
+
+    // Number of master clock ticks per CPU cycle
+    pub fn cpu_divisor(&self) -> u32 {
+        match self {
+            Region::Ntsc => 12,
+            Region::Pal => 16,
+            Region::Dendy => 15,
+        }
+    }
+
+    // Number of master clock ticks per PPU dot
+    pub fn ppu_divisor(&self) -> u32 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+
+    // CPU frequency, in Hz
+    pub fn cpu_frequency(&self) -> f64 {
+        self.master_clock() / self.cpu_divisor() as f64
+    }
+
+    // Scanlines per frame, including vblank and pre-render lines
+    pub fn scanlines(&self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    // First scanline of the vertical blanking period
+    pub fn vblank_scanline(&self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    // Default RGB colors of the 64 palette entries
+    pub fn palette(&self) -> &'static [(u8, u8, u8); 64] {
+        match self {
+            Region::Ntsc => &NTSC_PALETTE,
+            Region::Pal | Region::Dendy => &PAL_PALETTE,
+        }
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ntsc" => Ok(Region::Ntsc),
+            "pal" => Ok(Region::Pal),
+            "dendy" => Ok(Region::Dendy),
+            _ => Err(format!("'{}' is not a region (ntsc, pal or dendy)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Region::Ntsc => "NTSC",
+            Region::Pal => "PAL",
+            Region::Dendy => "Dendy",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pal_timings() {
+        let pal = Region::Pal;
+        assert_eq!(pal.scanlines(), 312);
+        assert_eq!(pal.cpu_frequency().round(), 1_662_607.0);
+    }
+
+    #[test]
+    fn test_ntsc_timings() {
+        let ntsc = Region::Ntsc;
+        assert_eq!(ntsc.scanlines(), 262);
+        assert_eq!(ntsc.cpu_frequency().round(), 1_789_773.0);
+        assert_eq!(ntsc.palette()[0x30], (236, 238, 236));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("pal".parse(), Ok(Region::Pal));
+        assert_eq!("NTSC".parse(), Ok(Region::Ntsc));
+        assert_eq!("Dendy".parse::<Region>().unwrap().to_string(), "Dendy");
+        assert!("secam".parse::<Region>().is_err());
+    }
+}
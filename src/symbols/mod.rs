@@ -0,0 +1,137 @@
+#![allow(dead_code)]
+// Debugger label files from other emulators, mapped to CPU addresses
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub type Symbols = BTreeMap<u16, String>;
+
+// Load a label file, the format is picked from the extension
+pub fn load(path: &Path) -> Result<Symbols, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("nl") => parse_nl(&text, nl_bank(name)),
+        Some("mlb") => parse_mlb(&text),
+        _ => Err(format!("Unknown symbol file format: {}", path.display())),
+    }
+}
+
+// FCEUX names its files `game.nes.ram.nl` for RAM, `game.nes.N.nl` for PRG bank N
+fn nl_bank(name: &str) -> Option<usize> {
+    let stem = name.strip_suffix(".nl")?;
+    let (_, bank) = stem.rsplit_once('.')?;
+    bank.parse().ok()
+}
+
+// FCEUX: `$C000#Reset#optional comment`, or `$0200/10#Buffer#` for an array.
+// Bank files hold CPU addresses already. Without a mapper only one set of banks
+// is visible, so `bank` is only checked against the address range.
+pub fn parse_nl(text: &str, bank: Option<usize>) -> Result<Symbols, String> {
+    let mut symbols = Symbols::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = || format!("Invalid .nl line {}: '{}'", number + 1, line);
+
+        let mut fields = line.splitn(3, '#');
+        let addr = fields.next().ok_or_else(error)?;
+        let label = fields.next().ok_or_else(error)?;
+        // array size, only the base address gets the label
+        let addr = addr.split('/').next().ok_or_else(error)?;
+        let addr = addr.strip_prefix('$').ok_or_else(error)?;
+        let addr = u16::from_str_radix(addr, 16).map_err(|_| error())?;
+
+        if bank.is_some() && addr < 0x8000 {
+            return Err(format!("{}: bank labels must be in $8000-$FFFF", error()));
+        }
+        if !label.is_empty() {
+            symbols.insert(addr, label.to_string());
+        }
+    }
+    Ok(symbols)
+}
+
+// Mesen: `TYPE:ADDR[-END]:LABEL[:comment]`, ADDR being an offset in the memory of TYPE.
+// Both the Mesen 1 letters and the Mesen 2 names are accepted.
+pub fn parse_mlb(text: &str) -> Result<Symbols, String> {
+    let mut symbols = Symbols::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = || format!("Invalid .mlb line {}: '{}'", number + 1, line);
+
+        let mut fields = line.splitn(4, ':');
+        let kind = fields.next().ok_or_else(error)?;
+        let offset = fields.next().ok_or_else(error)?;
+        let label = fields.next().ok_or_else(error)?;
+        let offset = offset.split('-').next().ok_or_else(error)?;
+        let offset = u16::from_str_radix(offset, 16).map_err(|_| error())?;
+
+        let addr = match kind {
+            // 2KB internal RAM
+            "R" | "NesInternalRam" => offset & 0x07FF,
+            // PRG ROM, as seen through NROM
+            "P" | "NesPrgRom" => 0x8000 | (offset & 0x7FFF),
+            // PRG RAM
+            "S" | "W" | "NesSaveRam" | "NesWorkRam" => 0x6000 | (offset & 0x1FFF),
+            // registers, already CPU addresses
+            "G" | "NesMemory" => offset,
+            _ => return Err(format!("{}: unknown memory type", error())),
+        };
+        if !label.is_empty() {
+            symbols.insert(addr, label.to_string());
+        }
+    }
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_nl() {
+        let text = "$C000#Reset#entry point\n$C0F3#NMI#\n$C100##comment only\n$8000/20#Table#\n";
+        let symbols = parse_nl(text, Some(1)).unwrap();
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols[&0xC000], "Reset");
+        assert_eq!(symbols[&0xC0F3], "NMI");
+        assert_eq!(symbols[&0x8000], "Table");
+
+        let symbols = parse_nl("$0010#ptr#\n", None).unwrap();
+        assert_eq!(symbols[&0x0010], "ptr");
+        // RAM address in a PRG bank file
+        assert!(parse_nl("$0010#ptr#\n", Some(0)).is_err());
+        assert!(parse_nl("C000#Reset#\n", None).is_err());
+
+        assert_eq!(nl_bank("game.nes.0.nl"), Some(0));
+        assert_eq!(nl_bank("game.nes.12.nl"), Some(12));
+        assert_eq!(nl_bank("game.nes.ram.nl"), None);
+    }
+
+    #[test]
+    fn test_parse_mlb() {
+        let text = "P:0000:Reset\nP:40F3:NMI:handler\nR:0010-0011:ptr\nS:0000:save\n\
+                    G:2002:PPUSTATUS\nNesInternalRam:0300:buffer\nR:0020::comment only\n";
+        let symbols = parse_mlb(text).unwrap();
+        assert_eq!(symbols.len(), 6);
+        assert_eq!(symbols[&0x8000], "Reset");
+        assert_eq!(symbols[&0xC0F3], "NMI");
+        assert_eq!(symbols[&0x0010], "ptr");
+        assert_eq!(symbols[&0x6000], "save");
+        assert_eq!(symbols[&0x2002], "PPUSTATUS");
+        assert_eq!(symbols[&0x0300], "buffer");
+
+        assert!(parse_mlb("X:0000:what\n").is_err());
+        assert!(parse_mlb("P:zz:bad\n").is_err());
+    }
+}
@@ -0,0 +1,74 @@
+// CPU validation with nestest: https://www.qmtpro.com/~nes/misc/nestest.txt
+// Run from $C000 (automation mode), the ROM goes through every official opcode
+// then the illegal ones, and the trace must match the reference log line by line.
+use std::path::Path;
+use yane2::bus::Bus;
+use yane2::cartridge::Cartridge;
+use yane2::cpu::Cpu;
+use yane2::ines::INes;
+
+// Both sides without the PPU column (there is no PPU yet) and with whitespace collapsed
+fn normalize(line: &str) -> String {
+    line.split_whitespace()
+        .scan(false, |ppu, field| {
+            // `PPU:  0, 21` spans up to 3 fields, it ends at the `CYC:` one
+            if field.starts_with("PPU:") {
+                *ppu = true;
+            } else if field.starts_with("CYC:") {
+                *ppu = false;
+            }
+            Some((!*ppu).then_some(field))
+        })
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[test]
+fn nestest() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let (rom, log) = (root.join("nestest.nes"), root.join("nestest.log"));
+    if !rom.exists() || !log.exists() {
+        eprintln!("nestest.nes or nestest.log not found, skipping");
+        return;
+    }
+    let rom = INes::from_file(&rom).unwrap();
+    let log = std::fs::read_to_string(log).unwrap();
+
+    let mut bus = Bus::new();
+    bus.attach_cartridge(Cartridge::new(&rom).unwrap()).unwrap();
+    let mut cpu = Cpu::new(bus);
+    cpu.power_on();
+    cpu.step_n(1); // reset sequence
+    cpu.core.pc = 0xC000;
+
+    let mut checked = 0;
+    for (number, expected) in log.lines().enumerate() {
+        let opcode = cpu.bus().peek(cpu.core.pc);
+        if cpu.mnemonic(opcode) == "???" {
+            // the illegal opcodes other than NOPs are not supported (yet)
+            eprintln!(
+                "nestest: stopping at ${:04X}, opcode ${:02X} is not implemented",
+                cpu.core.pc, opcode
+            );
+            break;
+        }
+
+        let actual = cpu.trace_line();
+        assert_eq!(
+            normalize(&actual),
+            normalize(expected),
+            "nestest diverges at ${:04X} (line {})",
+            cpu.core.pc,
+            number + 1
+        );
+        checked += 1;
+        cpu.step_n(1);
+    }
+
+    // the official opcodes end at line 5003
+    assert!(checked >= 5003, "only {} lines checked", checked);
+    // error codes of the official opcode tests, 0 when they all pass
+    let bus = cpu.bus();
+    assert_eq!((bus.peek(0x0002), bus.peek(0x0003)), (0x00, 0x00));
+}